@@ -0,0 +1,43 @@
+/*
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+//! Parse untrusted misc partition images. Run with `cargo fuzz run misc_parse`.
+
+#![no_main]
+
+use libcore::bootloader::kvstore::MiscKeyValueStore;
+use libcore::bootloader::message::{BootloaderControl, BootloaderMessageAB};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message_ab) = BootloaderMessageAB::from_bytes(data) {
+        // parsing and serializing must not change the data
+        assert_eq!(&message_ab.to_bytes()[..], &data[0..4096]);
+
+        let _ = message_ab.message.command();
+        let _ = message_ab.message.recovery_args();
+        let _ = message_ab.message.stage();
+        if let Ok(control) = message_ab.get_bootloader_control() {
+            let _ = control.slot_suffix();
+            assert_eq!(&control.to_bytes()[..], &data[2048..2080]);
+        }
+        let _ = message_ab.to_json();
+    }
+
+    if let Ok(control) = BootloaderControl::from_bytes(data) {
+        assert_eq!(&control.to_bytes()[0..28], &data[0..28]);
+    }
+
+    let _ = MiscKeyValueStore::from_bytes(data);
+});
@@ -0,0 +1,130 @@
+/*
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+//! Command line access to the boot control HAL. Works on the misc partition
+//! of the device, or on a misc image file with --image.
+
+use std::{path::Path, process::exit};
+
+use libcore::bootloader::{
+    bootcontrol::BootControlImpl,
+    storage::{FileStorage, MiscStorage},
+};
+use sabaton_hal::bootloader::BootControl;
+
+const USAGE: &str = r###"Usage: bootctl [--image <misc image>] <command> [<slot>]
+
+Commands:
+  get-number-slots               Print the number of slots
+  get-current-slot               Print the slot that is currently running
+  mark-boot-successful           Mark the current slot as successfully booted
+  set-active-boot-slot <slot>    Boot from <slot> on the next boot
+  set-slot-as-unbootable <slot>  Mark <slot> as unbootable
+  is-slot-bootable <slot>        Exit with 0 if <slot> is bootable, 1 otherwise
+  is-slot-marked-successful <slot>
+                                 Exit with 0 if <slot> booted successfully, 1 otherwise
+  dump                           Print the boot control metadata
+"###;
+
+fn usage() -> ! {
+    eprint!("{}", USAGE);
+    exit(2)
+}
+
+fn parse_slot(slot: Option<String>) -> usize {
+    match slot.as_deref().map(str::parse::<usize>) {
+        Some(Ok(slot)) => slot,
+        _ => usage(),
+    }
+}
+
+fn exit_with(result: bool) -> ! {
+    exit(if result { 0 } else { 1 })
+}
+
+fn dump(boot_control: &BootControlImpl) -> Result<(), std::io::Error> {
+    let message = boot_control.bootloader_message();
+    let control = message
+        .get_bootloader_control()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+    println!(
+        "Active slot suffix: {}",
+        control
+            .slot_suffix()
+            .map_or_else(|e| format!("<{}>", e), |s| s.to_string_lossy().into_owned())
+    );
+    println!("Number of slots: {}", control.nb_slot());
+    println!(
+        "Recovery tries remaining: {}",
+        control.recovery_tries_remaining()
+    );
+    for (index, slot) in control.slot_info[0..control.slot_count()]
+        .iter()
+        .enumerate()
+    {
+        println!("Slot {}: {}", index, slot);
+    }
+    println!(
+        "Update channel: {}",
+        message
+            .update_channel()
+            .map_or_else(|e| format!("<{}>", e), String::from)
+    );
+    Ok(())
+}
+
+fn run(mut args: impl Iterator<Item = String>) -> Result<(), std::io::Error> {
+    let mut command = args.next().unwrap_or_else(|| usage());
+    let image = if command == "--image" {
+        let image = args.next().unwrap_or_else(|| usage());
+        command = args.next().unwrap_or_else(|| usage());
+        Some(image)
+    } else {
+        None
+    };
+
+    if command == "-h" || command == "--help" {
+        usage();
+    }
+
+    let mut boot_control = if let Some(image) = image {
+        let storage: Box<dyn MiscStorage> = Box::new(FileStorage::open(Path::new(&image))?);
+        BootControlImpl::create_from_storage(storage)?
+    } else {
+        BootControlImpl::create()?
+    };
+
+    match command.as_str() {
+        "get-number-slots" => println!("{}", boot_control.number_of_slots()?),
+        "get-current-slot" => println!("{}", boot_control.current_slot()?),
+        "mark-boot-successful" => boot_control.set_boot_successful()?,
+        "set-active-boot-slot" => boot_control.set_active_slot(parse_slot(args.next()))?,
+        "set-slot-as-unbootable" => boot_control.set_slot_as_unbootable(parse_slot(args.next()))?,
+        "is-slot-bootable" => exit_with(boot_control.is_bootable(parse_slot(args.next()))?),
+        "is-slot-marked-successful" => {
+            exit_with(boot_control.is_slot_successful(parse_slot(args.next()))?)
+        }
+        "dump" => dump(&boot_control)?,
+        _ => usage(),
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run(std::env::args().skip(1)) {
+        eprintln!("bootctl: {}", e);
+        exit(1);
+    }
+}
@@ -11,106 +11,558 @@
    See the License for the specific language governing permissions and
    limitations under the License.
 */
-use std::io::Error;
+use super::error::BootloaderMessageError;
+use super::message::{
+    BootloaderCommand, BootloaderMessageAB, MergeStatus, MiscVirtualABMessage, MAX_TRIES,
+};
+use super::rollback_index::RollbackIndexes;
+use super::storage::{BlockDeviceStorage, MiscStorage};
 use sabaton_hal::bootloader::{get_slot_suffix_from_cmd_line, BootControl};
-use super::message::BootloaderMessageAB;
+use std::{cell::Cell, convert::TryFrom, fmt::Display, io::Error};
 
-pub struct BootControlImpl(BootloaderMessageAB);
+/// Suffixes of the slots, indexed by slot number. The BootloaderControl
+/// structure has room for up to four slots.
+const SLOT_SUFFIXES: [&str; 4] = ["a", "b", "c", "d"];
+
+/// Get the partition suffix for the slot index. Slot 0 is "a", slot 1 is "b" and so on.
+pub fn slot_suffix_from_index(slot_index: usize) -> Result<&'static str, std::io::Error> {
+    SLOT_SUFFIXES.get(slot_index).copied().ok_or_else(|| {
+        Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid slot index : {}", slot_index),
+        )
+    })
+}
+
+/// Get the slot index for a partition suffix. A leading underscore is ignored
+/// so that both "b" and "_b" map to slot 1.
+pub fn slot_index_from_suffix(suffix: &str) -> Result<usize, std::io::Error> {
+    let suffix = suffix.trim_start_matches('_');
+    SLOT_SUFFIXES
+        .iter()
+        .position(|s| *s == suffix)
+        .ok_or_else(|| {
+            Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Invalid slot suffix : {}", suffix),
+            )
+        })
+}
+
+const BOOTCONFIG_LOCATION: &str = "/proc/bootconfig";
+const CMDLINE_LOCATION: &str = "/proc/cmdline";
+const SLOT_SUFFIX_PARAMETER: &str = "androidboot.slot_suffix";
+
+/// Where the current slot was found
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlotSource {
+    Bootconfig,
+    CommandLine,
+    Misc,
+}
+
+impl Display for SlotSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SlotSource::Bootconfig => write!(f, "{}", BOOTCONFIG_LOCATION),
+            SlotSource::CommandLine => write!(f, "{}", CMDLINE_LOCATION),
+            SlotSource::Misc => write!(f, "the misc partition"),
+        }
+    }
+}
+
+/// Get the slot suffix from the contents of /proc/bootconfig. The entry
+/// is of the form `androidboot.slot_suffix = "_a"`.
+pub fn get_slot_suffix_from_bootconfig(bootconfig: &str) -> Option<&str> {
+    bootconfig.lines().find_map(|line| {
+        let (key, value) = line.split_once('=')?;
+        if key.trim() == SLOT_SUFFIX_PARAMETER {
+            Some(value.trim().trim_matches('"'))
+        } else {
+            None
+        }
+    })
+}
+
+/// Find the current slot in the first source that has a slot suffix. Bootconfig
+/// is checked first, then the kernel command line, then the slot suffix in misc.
+fn find_current_slot(
+    bootconfig: Option<&str>,
+    command_line: Option<&str>,
+    misc_slot_suffix: Option<&str>,
+) -> Result<(usize, SlotSource), std::io::Error> {
+    let (suffix, source) = if let Some(suffix) =
+        bootconfig.and_then(get_slot_suffix_from_bootconfig)
+    {
+        (suffix, SlotSource::Bootconfig)
+    } else if let Some(suffix) = command_line.and_then(|c| get_slot_suffix_from_cmd_line(c).ok()) {
+        (suffix, SlotSource::CommandLine)
+    } else if let Some(suffix) = misc_slot_suffix {
+        (suffix, SlotSource::Misc)
+    } else {
+        return Err(Error::new(
+            std::io::ErrorKind::NotFound,
+            format!(
+                "No slot suffix in {}, {} or {}",
+                SlotSource::Bootconfig,
+                SlotSource::CommandLine,
+                SlotSource::Misc
+            ),
+        ));
+    };
+
+    let slot_index = slot_index_from_suffix(suffix)
+        .map_err(|e| Error::new(e.kind(), format!("{} from {}", e, source)))?;
+    Ok((slot_index, source))
+}
+
+/// Boot control operations that verified boot needs on top of the HAL. All the
+/// boot control backends implement it.
+pub trait VerifiedBootControl: BootControl {
+    /// The storage that holds the anti-rollback indexes, None if the backend
+    /// has no place for them. Rollback protection is then not available.
+    fn rollback_index_storage(&mut self) -> Option<&mut dyn MiscStorage> {
+        None
+    }
+
+    /// Record that dm-verity found the slot corrupted. If another slot is bootable,
+    /// the corrupted slot is made unbootable so that the bootloader falls back to
+    /// the other slot on the next boot. Backends that have no verity corrupted flag
+    /// only make the slot unbootable.
+    fn set_slot_verity_corrupted(&mut self, slot_index: usize) -> Result<(), std::io::Error> {
+        let mut other_slot_bootable = false;
+        for index in 0..self.number_of_slots()? {
+            if index != slot_index && self.is_bootable(index)? {
+                other_slot_bootable = true;
+            }
+        }
+
+        if other_slot_bootable {
+            self.set_slot_as_unbootable(slot_index)
+        } else {
+            log::error!("No other bootable slot, keeping the corrupted slot bootable");
+            Ok(())
+        }
+    }
+}
+
+/// Map an error of the bootloader control for the slot to an io error
+fn invalid_slot_error(e: BootloaderMessageError, slot_index: usize) -> std::io::Error {
+    match e {
+        BootloaderMessageError::InvalidSlotIndex => Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid slot index : {}", slot_index),
+        ),
+        e => Error::new(std::io::ErrorKind::InvalidData, e),
+    }
+}
+
+pub struct BootControlImpl {
+    message: BootloaderMessageAB,
+    storage: Box<dyn MiscStorage>,
+    backup_copy: bool,
+    /// The current slot does not change until the next boot
+    current_slot: Cell<Option<(usize, SlotSource)>>,
+}
 
 impl BootControlImpl {
+    /// Create the boot control over the misc partition of this device
     pub fn create() -> Result<Self, std::io::Error> {
-        BootloaderMessageAB::create_from_misc_partition().map(Self)
+        Self::create_from_storage(Box::new(BlockDeviceStorage::misc()?))
     }
-}
 
-impl BootControl for BootControlImpl {
-    fn number_of_slots(&self) -> Result<usize, std::io::Error> {
+    /// Create the boot control over the misc partition of this device and keep
+    /// a backup copy of the metadata in the vendor space of misc.
+    pub fn create_with_backup() -> Result<Self, std::io::Error> {
+        Self::create_from_storage_with_backup(Box::new(BlockDeviceStorage::misc()?))
+    }
+
+    /// Create the boot control over any misc storage, for example an image file
+    pub fn create_from_storage(storage: Box<dyn MiscStorage>) -> Result<Self, std::io::Error> {
+        let message = BootloaderMessageAB::create_from_storage(storage.as_ref())?;
+        Ok(Self {
+            message,
+            storage,
+            backup_copy: false,
+            current_slot: Cell::new(None),
+        })
+    }
+
+    /// Create the boot control over any misc storage and keep a backup copy of
+    /// the metadata. The newest valid copy is used.
+    pub fn create_from_storage_with_backup(
+        storage: Box<dyn MiscStorage>,
+    ) -> Result<Self, std::io::Error> {
+        let message = BootloaderMessageAB::create_from_storage_with_backup(storage.as_ref())?;
+        Ok(Self {
+            message,
+            storage,
+            backup_copy: true,
+            current_slot: Cell::new(None),
+        })
+    }
+
+    /// Write fresh boot control metadata for the number of slots. All slots get
+    /// the default priority and tries and the first slot is made active.
+    pub fn initialize_metadata(&mut self, number_of_slots: usize) -> Result<(), std::io::Error> {
+        let nb_slot = u8::try_from(number_of_slots).map_err(|_e| {
+            Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid number of slots : {}", number_of_slots),
+            )
+        })?;
+        self.message
+            .initialize_bootloader_control(nb_slot)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        self.save()
+    }
+
+    /// Initialize the boot control metadata if it is missing or corrupted.
+    /// Returns true if the metadata had to be initialized.
+    pub fn repair_metadata(&mut self, number_of_slots: usize) -> Result<bool, std::io::Error> {
+        match self.message.get_bootloader_control() {
+            Ok(_) => Ok(false),
+            Err(e) => {
+                log::warn!("Boot control metadata is invalid ({}), initializing", e);
+                self.initialize_metadata(number_of_slots)?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Keep a backup copy of the metadata in the vendor space of misc. The
+    /// backup is used by create_from_storage_with_backup if the primary copy
+    /// is found corrupted or older.
+    pub fn set_backup_copy(&mut self, enabled: bool) {
+        self.backup_copy = enabled;
+    }
+
+    /// The bootloader message that holds the boot control metadata
+    pub fn bootloader_message(&self) -> &BootloaderMessageAB {
+        &self.message
+    }
+
+    pub fn is_slot_verity_corrupted(&self, slot_index: usize) -> Result<bool, std::io::Error> {
         let bl_control = self
-            .0
+            .message
             .get_bootloader_control()
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
 
-        Ok(bl_control.nb_slot() as usize)
+        if slot_index < bl_control.slot_count() {
+            Ok(bl_control.slot_info[slot_index].verity_corrupted() == 1)
+        } else {
+            Err(Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid slot index : {}", slot_index),
+            ))
+        }
     }
 
-    /// Get the current slot from the kernel command line
-    fn current_slot(&self) -> Result<usize, std::io::Error> {
-        let command_line = std::fs::read_to_string("/proc/cmdline")?;
-        match get_slot_suffix_from_cmd_line(&command_line)? {
-            "a" => Ok(0),
-            "b" => Ok(1),
-            s => Err(Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Invalid slot suffix : {}", s),
-            )),
+    /// Request a boot into recovery with the arguments. The bootloader uses up one
+    /// of the tries on every boot into recovery, so a recovery that keeps failing
+    /// is not entered again once the tries are exhausted.
+    pub fn arm_recovery(&mut self, tries: u8, args: &[&str]) -> Result<(), std::io::Error> {
+        if tries == 0 || tries > MAX_TRIES {
+            return Err(Error::new(
+                std::io::ErrorKind::InvalidInput,
+                BootloaderMessageError::TriesOutOfRange,
+            ));
         }
+        let mut bl_control = self
+            .message
+            .get_bootloader_control()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+        bl_control.set_recovery_tries_remaining(tries);
+        self.message.set_bootloader_control(&bl_control);
+        self.message
+            .message
+            .set_boot_recovery(args)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        self.save()
     }
 
-    fn set_boot_successful(&mut self) -> Result<(), std::io::Error> {
-        let current_slot = self.current_slot()?;
+    /// Use up one recovery try. Returns the tries remaining, or an error if
+    /// recovery was entered without any tries left.
+    pub fn use_recovery_try(&mut self) -> Result<u8, std::io::Error> {
+        let mut bl_control = self
+            .message
+            .get_bootloader_control()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
 
-        let bl_control = self
-            .0
-            .get_bootloader_control_mut()
+        let tries = bl_control
+            .use_recovery_try()
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        self.message.set_bootloader_control(&bl_control);
+        self.save()?;
+        Ok(tries)
+    }
 
-        bl_control.slot_info[current_slot].set_successful_boot(1);
+    /// Called by recovery once it is done. The recovery request and the
+    /// remaining tries are cleared so that the next boot is a normal boot.
+    pub fn mark_recovery_successful(&mut self) -> Result<(), std::io::Error> {
+        let mut bl_control = self
+            .message
+            .get_bootloader_control()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
 
-        self.0.save_to_misc_partition()
+        bl_control.set_recovery_tries_remaining(0);
+        self.message.set_bootloader_control(&bl_control);
+        self.message.message.clear_command();
+        self.message.message.clear_recovery_args();
+        self.save()
     }
 
-    fn set_active_slot(&mut self, slot_index: usize) -> Result<(), std::io::Error> {
+    pub fn recovery_tries_remaining(&self) -> Result<u8, std::io::Error> {
         let bl_control = self
-            .0
-            .get_bootloader_control_mut()
+            .message
+            .get_bootloader_control()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        Ok(bl_control.recovery_tries_remaining())
+    }
+
+    /// True if a boot into recovery is requested but no tries are left. The
+    /// recovery image keeps failing and must not be entered again.
+    pub fn is_recovery_exhausted(&self) -> Result<bool, std::io::Error> {
+        let command = self
+            .message
+            .message
+            .command()
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        Ok(command == Some(BootloaderCommand::BootRecovery)
+            && self.recovery_tries_remaining()? == 0)
+    }
 
-        if slot_index < bl_control.nb_slot() as usize {
-            let suffix = match slot_index {
-                0 => "a",
-                1 => "b",
-                _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "")),
-            };
+    /// Get the merge status of a virtual A/B update. A missing or invalid virtual
+    /// A/B message means that no update is in progress. If the partitions are
+    /// snapshotted but the slot that wrote the status is running again, the
+    /// update was rolled back and is reported as cancelled.
+    pub fn snapshot_merge_status(&self) -> Result<MergeStatus, std::io::Error> {
+        let message = match MiscVirtualABMessage::load(self.storage.as_ref()) {
+            Ok(message) => message,
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                log::debug!("No valid virtual A/B message : {}", e);
+                return Ok(MergeStatus::None);
+            }
+            Err(e) => return Err(e),
+        };
 
-            bl_control
-                .set_slot_suffix(suffix)
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        match message.merge_status() {
+            MergeStatus::Snapshotted if message.source_slot as usize == self.current_slot()? => {
+                Ok(MergeStatus::Cancelled)
+            }
+            merge_status => Ok(merge_status),
+        }
+    }
 
-            self.0.save_to_misc_partition()
-        } else {
+    /// Set the merge status of a virtual A/B update. The current slot is
+    /// recorded along with the status.
+    pub fn set_snapshot_merge_status(
+        &mut self,
+        merge_status: MergeStatus,
+    ) -> Result<(), std::io::Error> {
+        let mut message = MiscVirtualABMessage::load(self.storage.as_ref()).unwrap_or_default();
+        let current_slot = self.current_slot()?;
+        message.set_merge_status(merge_status, current_slot as u8);
+        message.save(self.storage.as_mut())
+    }
+
+    /// Start installing an update into the slot. The slot is made unbootable
+    /// before anything is written to it, so that the bootloader never picks a
+    /// half written slot. The running slot cannot be updated.
+    pub fn begin_update(&mut self, slot_index: usize) -> Result<(), std::io::Error> {
+        if self.snapshot_merge_status()? == MergeStatus::Merging {
+            return Err(Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                BootloaderMessageError::SnapshotMergeInProgress,
+            ));
+        }
+        self.check_update_slot(slot_index)?;
+        self.set_slot_as_unbootable(slot_index)
+    }
+
+    /// Finish installing an update into the slot once it is verified. The slot
+    /// gets the highest priority and a full count of tries and is made active,
+    /// so that it is booted next.
+    pub fn finish_update(&mut self, slot_index: usize) -> Result<(), std::io::Error> {
+        self.check_update_slot(slot_index)?;
+        self.set_active_slot(slot_index)
+    }
+
+    fn check_update_slot(&self, slot_index: usize) -> Result<(), std::io::Error> {
+        if slot_index >= self.number_of_slots()? {
             Err(Error::new(
                 std::io::ErrorKind::InvalidInput,
                 format!("Invalid slot index : {}", slot_index),
             ))
+        } else if slot_index == self.current_slot()? {
+            Err(Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Slot {} is running and cannot be updated", slot_index),
+            ))
+        } else {
+            Ok(())
         }
     }
 
-    fn set_slot_as_unbootable(&mut self, slot_index: usize) -> Result<(), std::io::Error> {
+    /// Get the stored anti-rollback index of the location. A blank store reads
+    /// as 0, a corrupted one is an error.
+    pub fn rollback_index(&self, location: usize) -> Result<u64, std::io::Error> {
+        let indexes = self.rollback_indexes()?;
+        indexes
+            .rollback_index(location)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+    }
+
+    /// Check the rollback index of the image in the current slot and stage it.
+    /// It is committed once the boot is marked successful.
+    pub fn stage_rollback_index(
+        &mut self,
+        location: usize,
+        index: u64,
+    ) -> Result<(), std::io::Error> {
+        let current_slot = self.current_slot()?;
+        let mut indexes = self.rollback_indexes()?;
+        indexes
+            .stage(current_slot, location, index)
+            .map_err(|e| match e {
+                BootloaderMessageError::RollbackIndexTooLow => {
+                    std::io::Error::new(std::io::ErrorKind::PermissionDenied, e)
+                }
+                e => std::io::Error::new(std::io::ErrorKind::InvalidInput, e),
+            })?;
+        indexes.save(self.storage.as_mut())
+    }
+
+    fn rollback_indexes(&self) -> Result<RollbackIndexes, std::io::Error> {
+        RollbackIndexes::load(self.storage.as_ref()).map_err(|e| {
+            log::error!("Cannot load the rollback indexes : {}", e);
+            e
+        })
+    }
+
+    /// Get the current slot and where it was found
+    pub fn current_slot_with_source(&self) -> Result<(usize, SlotSource), std::io::Error> {
+        if let Some(current_slot) = self.current_slot.get() {
+            return Ok(current_slot);
+        }
+
+        let bootconfig = std::fs::read_to_string(BOOTCONFIG_LOCATION).ok();
+        let command_line = std::fs::read_to_string(CMDLINE_LOCATION).ok();
+        let bl_control = self.message.get_bootloader_control().ok();
+        let misc_slot_suffix = bl_control
+            .as_ref()
+            .and_then(|c| c.slot_suffix().ok())
+            .and_then(|s| s.to_str().ok());
+
+        let current_slot = find_current_slot(
+            bootconfig.as_deref(),
+            command_line.as_deref(),
+            misc_slot_suffix,
+        )?;
+        log::debug!("Current slot is {} from {}", current_slot.0, current_slot.1);
+        self.current_slot.set(Some(current_slot));
+        Ok(current_slot)
+    }
+
+    fn save(&mut self) -> Result<(), std::io::Error> {
+        if self.backup_copy {
+            self.message
+                .save_to_storage_with_backup(self.storage.as_mut())
+        } else {
+            self.message.save_to_storage(self.storage.as_mut())
+        }
+    }
+}
+
+impl BootControl for BootControlImpl {
+    fn number_of_slots(&self) -> Result<usize, std::io::Error> {
         let bl_control = self
-            .0
-            .get_bootloader_control_mut()
+            .message
+            .get_bootloader_control()
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
 
-        if slot_index < bl_control.nb_slot() as usize {
-            bl_control.slot_info[slot_index].set_tries_remaining(0);
-        } else {
+        Ok(bl_control.slot_count())
+    }
+
+    /// Get the current slot from bootconfig, the kernel command line or misc
+    fn current_slot(&self) -> Result<usize, std::io::Error> {
+        self.current_slot_with_source()
+            .map(|(slot_index, _)| slot_index)
+    }
+
+    fn set_boot_successful(&mut self) -> Result<(), std::io::Error> {
+        let current_slot = self.current_slot()?;
+        // fail before anything is written if the rollback indexes are corrupted
+        let mut indexes = self.rollback_indexes()?;
+
+        let mut bl_control = self
+            .message
+            .get_bootloader_control()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+        bl_control.slot_info[current_slot].set_successful_boot(1);
+
+        self.message.set_bootloader_control(&bl_control);
+        self.save()?;
+
+        // only now is the slot known to work, raise the rollback indexes to its images
+        if indexes
+            .commit(current_slot)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?
+        {
+            indexes.save(self.storage.as_mut())?;
+        }
+        Ok(())
+    }
+
+    /// Make the slot active with the highest priority and a full count of tries.
+    /// Switching slots is refused while a snapshot merge is in progress.
+    fn set_active_slot(&mut self, slot_index: usize) -> Result<(), std::io::Error> {
+        if self.snapshot_merge_status()? == MergeStatus::Merging {
             return Err(Error::new(
-                std::io::ErrorKind::InvalidInput,
-                format!("Invalid slot index : {}", slot_index),
+                std::io::ErrorKind::PermissionDenied,
+                BootloaderMessageError::SnapshotMergeInProgress,
             ));
         }
 
-        self.0.save_to_misc_partition()
+        let mut bl_control = self
+            .message
+            .get_bootloader_control()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+        // the running slot must be known, it keeps its successful boot flag
+        let is_current_slot = self.current_slot()? == slot_index;
+        bl_control
+            .set_active_slot(slot_index, is_current_slot)
+            .map_err(|e| invalid_slot_error(e, slot_index))?;
+
+        self.message.set_bootloader_control(&bl_control);
+        self.save()
+    }
+
+    fn set_slot_as_unbootable(&mut self, slot_index: usize) -> Result<(), std::io::Error> {
+        let mut bl_control = self
+            .message
+            .get_bootloader_control()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+        bl_control
+            .set_slot_unbootable(slot_index)
+            .map_err(|e| invalid_slot_error(e, slot_index))?;
+
+        self.message.set_bootloader_control(&bl_control);
+        self.save()
     }
 
     fn is_bootable(&self, slot_index: usize) -> Result<bool, std::io::Error> {
         let bl_control = self
-            .0
+            .message
             .get_bootloader_control()
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
 
-        if slot_index < bl_control.nb_slot() as usize {
-            Ok(bl_control.slot_info[slot_index].tries_remaining() > 0)
+        if slot_index < bl_control.slot_count() {
+            Ok(bl_control.slot_info[slot_index].is_bootable())
         } else {
             Err(Error::new(
                 std::io::ErrorKind::InvalidInput,
@@ -121,11 +573,11 @@ impl BootControl for BootControlImpl {
 
     fn is_slot_successful(&self, slot_index: usize) -> Result<bool, std::io::Error> {
         let bl_control = self
-            .0
+            .message
             .get_bootloader_control()
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
 
-        if slot_index < bl_control.nb_slot() as usize {
+        if slot_index < bl_control.slot_count() {
             Ok(bl_control.slot_info[slot_index].successful_boot() == 1)
         } else {
             Err(Error::new(
@@ -137,7 +589,7 @@ impl BootControl for BootControlImpl {
 
     fn active_slot(&self) -> Result<usize, std::io::Error> {
         let bl_control = self
-            .0
+            .message
             .get_bootloader_control()
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
 
@@ -145,15 +597,304 @@ impl BootControl for BootControlImpl {
             .slot_suffix()
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
 
-        Ok(
-            match active_slot
+        slot_index_from_suffix(
+            active_slot
                 .to_str()
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?
-            {
-                "a" => 0,
-                "b" => 1,
-                _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "")),
-            },
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?,
         )
     }
 }
+
+impl VerifiedBootControl for BootControlImpl {
+    fn rollback_index_storage(&mut self) -> Option<&mut dyn MiscStorage> {
+        Some(self.storage.as_mut())
+    }
+
+    fn set_slot_verity_corrupted(&mut self, slot_index: usize) -> Result<(), std::io::Error> {
+        let mut bl_control = self
+            .message
+            .get_bootloader_control()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+        bl_control
+            .set_slot_verity_corrupted(slot_index)
+            .map_err(|e| invalid_slot_error(e, slot_index))?;
+
+        self.message.set_bootloader_control(&bl_control);
+        self.save()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bootloader::message::MAX_SLOT_PRIORITY;
+    use crate::bootloader::rollback_index::ROLLBACK_INDEX_OFFSET_IN_MISC;
+    use crate::bootloader::storage::{misc_from_testdata, temp_path, FileStorage};
+
+    fn boot_control_from_testdata() -> BootControlImpl {
+        BootControlImpl::create_from_storage(Box::new(misc_from_testdata())).unwrap()
+    }
+
+    #[test]
+    fn slot_suffix_mapping() {
+        for (index, suffix) in ["a", "b", "c", "d"].iter().enumerate() {
+            assert_eq!(slot_suffix_from_index(index).unwrap(), *suffix);
+            assert_eq!(slot_index_from_suffix(suffix).unwrap(), index);
+        }
+        assert_eq!(slot_index_from_suffix("_c").unwrap(), 2);
+        assert!(slot_suffix_from_index(4).is_err());
+        assert!(slot_index_from_suffix("e").is_err());
+        assert!(slot_index_from_suffix("").is_err());
+    }
+
+    #[test]
+    fn current_slot_sources() {
+        let bootconfig = "androidboot.hardware = \"qemu\"\nandroidboot.slot_suffix = \"_b\"\n";
+        let command_line = "console=ttyS0 androidboot.slot_suffix=_a quiet";
+        assert_eq!(get_slot_suffix_from_bootconfig(bootconfig), Some("_b"));
+        assert_eq!(get_slot_suffix_from_bootconfig(command_line), None);
+
+        assert_eq!(
+            find_current_slot(Some(bootconfig), Some(command_line), Some("a")).unwrap(),
+            (1, SlotSource::Bootconfig)
+        );
+        assert_eq!(
+            find_current_slot(Some(""), Some(command_line), Some("b")).unwrap(),
+            (0, SlotSource::CommandLine)
+        );
+        assert_eq!(
+            find_current_slot(None, Some("console=ttyS0"), Some("b")).unwrap(),
+            (1, SlotSource::Misc)
+        );
+        assert!(find_current_slot(None, None, None).is_err());
+
+        let error = find_current_slot(Some("androidboot.slot_suffix = \"_x\""), None, None)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains(BOOTCONFIG_LOCATION));
+    }
+
+    #[test]
+    fn boot_control_over_image_file() {
+        let path = temp_path("bootcontrol_over_image_file.img");
+        let mut storage = FileStorage::create(&path, 64 * 1024).unwrap();
+        storage
+            .write_at(misc_from_testdata().as_slice(), 0)
+            .unwrap();
+
+        let mut boot_control = BootControlImpl::create_from_storage(Box::new(storage)).unwrap();
+        assert_eq!(boot_control.number_of_slots().unwrap(), 2);
+        boot_control.set_active_slot(1).unwrap();
+        boot_control.set_slot_as_unbootable(0).unwrap();
+        assert!(boot_control.set_active_slot(2).is_err());
+
+        let storage = FileStorage::open(&path).unwrap();
+        let boot_control = BootControlImpl::create_from_storage(Box::new(storage)).unwrap();
+        assert_eq!(boot_control.active_slot().unwrap(), 1);
+        assert!(!boot_control.is_bootable(0).unwrap());
+        assert!(boot_control.is_bootable(1).unwrap());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn repair_blank_misc_partition() {
+        let path = temp_path("bootcontrol_repair_blank_misc_partition.img");
+        let storage = FileStorage::create(&path, 64 * 1024).unwrap();
+
+        let mut boot_control = BootControlImpl::create_from_storage(Box::new(storage)).unwrap();
+        assert!(boot_control.number_of_slots().is_err());
+        assert!(boot_control.repair_metadata(2).unwrap());
+        assert!(!boot_control.repair_metadata(2).unwrap());
+
+        let storage = FileStorage::open(&path).unwrap();
+        let boot_control = BootControlImpl::create_from_storage(Box::new(storage)).unwrap();
+        assert_eq!(boot_control.number_of_slots().unwrap(), 2);
+        assert_eq!(boot_control.active_slot().unwrap(), 0);
+        assert!(boot_control.is_bootable(1).unwrap());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn recovery_tries() {
+        let mut boot_control = boot_control_from_testdata();
+        assert!(boot_control.arm_recovery(8, &[]).is_err());
+        assert!(boot_control.arm_recovery(0, &[]).is_err());
+
+        boot_control.arm_recovery(2, &["--wipe_data"]).unwrap();
+        assert_eq!(boot_control.recovery_tries_remaining().unwrap(), 2);
+        assert_eq!(
+            boot_control
+                .bootloader_message()
+                .message
+                .recovery_args()
+                .unwrap(),
+            vec!["--wipe_data"]
+        );
+
+        // recovery crashes twice
+        assert_eq!(boot_control.use_recovery_try().unwrap(), 1);
+        assert!(!boot_control.is_recovery_exhausted().unwrap());
+        assert_eq!(boot_control.use_recovery_try().unwrap(), 0);
+        assert!(boot_control.is_recovery_exhausted().unwrap());
+        assert!(boot_control.use_recovery_try().is_err());
+
+        boot_control.arm_recovery(1, &[]).unwrap();
+        boot_control.mark_recovery_successful().unwrap();
+        assert!(!boot_control.is_recovery_exhausted().unwrap());
+        assert_eq!(
+            boot_control.bootloader_message().message.command().unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn no_slot_switch_during_merge() {
+        let mut boot_control = boot_control_from_testdata();
+        // booted from slot a
+        boot_control.current_slot.set(Some((0, SlotSource::Misc)));
+        assert_eq!(
+            boot_control.snapshot_merge_status().unwrap(),
+            MergeStatus::None
+        );
+
+        // the update to b is snapshotted on a, a is still running
+        boot_control
+            .set_snapshot_merge_status(MergeStatus::Snapshotted)
+            .unwrap();
+        assert_eq!(
+            boot_control.snapshot_merge_status().unwrap(),
+            MergeStatus::Cancelled
+        );
+        boot_control.set_active_slot(1).unwrap();
+
+        // b is running and merging
+        boot_control.current_slot.set(Some((1, SlotSource::Misc)));
+        assert_eq!(
+            boot_control.snapshot_merge_status().unwrap(),
+            MergeStatus::Snapshotted
+        );
+        boot_control
+            .set_snapshot_merge_status(MergeStatus::Merging)
+            .unwrap();
+        let error = boot_control.set_active_slot(0).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+        assert_eq!(boot_control.active_slot().unwrap(), 1);
+
+        boot_control
+            .set_snapshot_merge_status(MergeStatus::None)
+            .unwrap();
+        boot_control.set_active_slot(0).unwrap();
+    }
+
+    #[test]
+    fn record_verity_corruption() {
+        let mut boot_control = boot_control_from_testdata();
+        let mut control = boot_control.message.get_bootloader_control().unwrap();
+        control.slot_info[1].set_successful_boot(1);
+        boot_control.message.set_bootloader_control(&control);
+        boot_control.save().unwrap();
+
+        boot_control.set_slot_verity_corrupted(1).unwrap();
+        assert!(boot_control.is_slot_verity_corrupted(1).unwrap());
+        assert!(!boot_control.is_slot_verity_corrupted(0).unwrap());
+        assert!(!boot_control.is_bootable(1).unwrap());
+        assert!(!boot_control.is_slot_successful(1).unwrap());
+
+        // the last bootable slot is only marked, it keeps its tries
+        boot_control.set_slot_verity_corrupted(0).unwrap();
+        assert!(boot_control.is_slot_verity_corrupted(0).unwrap());
+        assert!(!boot_control.is_bootable(0).unwrap());
+        let control = boot_control.message.get_bootloader_control().unwrap();
+        assert_eq!(control.slot_info[0].tries_remaining(), 6);
+    }
+
+    #[test]
+    fn raise_rollback_index_after_successful_boot() {
+        let mut boot_control = boot_control_from_testdata();
+        boot_control.current_slot.set(Some((1, SlotSource::Misc)));
+        assert_eq!(boot_control.rollback_index(0).unwrap(), 0);
+
+        boot_control.stage_rollback_index(0, 3).unwrap();
+        assert_eq!(boot_control.rollback_index(0).unwrap(), 0);
+        boot_control.set_boot_successful().unwrap();
+        assert_eq!(boot_control.rollback_index(0).unwrap(), 3);
+
+        let error = boot_control.stage_rollback_index(0, 2).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+        assert!(boot_control.rollback_index(4).is_err());
+    }
+
+    #[test]
+    fn corrupted_rollback_indexes_are_not_reset() {
+        let mut boot_control = boot_control_from_testdata();
+        boot_control.current_slot.set(Some((1, SlotSource::Misc)));
+        boot_control.stage_rollback_index(0, 3).unwrap();
+        boot_control.set_boot_successful().unwrap();
+
+        // flip a bit in the stored indexes
+        let offset = ROLLBACK_INDEX_OFFSET_IN_MISC as u64 + 16;
+        let mut byte = [0u8; 1];
+        boot_control.storage.read_at(&mut byte, offset).unwrap();
+        byte[0] ^= 0x01;
+        boot_control.storage.write_at(&byte, offset).unwrap();
+
+        assert!(boot_control.rollback_index(0).is_err());
+        assert!(boot_control.stage_rollback_index(0, 1).is_err());
+        assert!(boot_control.set_boot_successful().is_err());
+
+        // the corrupted store was not replaced by zero indexes
+        let mut stored = [0u8; 1];
+        boot_control.storage.read_at(&mut stored, offset).unwrap();
+        assert_eq!(stored, byte);
+    }
+
+    #[test]
+    fn update_workflow() {
+        let mut boot_control = boot_control_from_testdata();
+        boot_control.current_slot.set(Some((0, SlotSource::Misc)));
+        boot_control.set_boot_successful().unwrap();
+
+        assert!(boot_control.begin_update(0).is_err());
+        assert!(boot_control.begin_update(2).is_err());
+
+        boot_control.begin_update(1).unwrap();
+        assert!(!boot_control.is_bootable(1).unwrap());
+        let control = boot_control.message.get_bootloader_control().unwrap();
+        assert_eq!(control.slot_info[1].priority(), 0);
+        assert_eq!(boot_control.active_slot().unwrap(), 0);
+
+        boot_control.finish_update(1).unwrap();
+        assert_eq!(boot_control.active_slot().unwrap(), 1);
+        let control = boot_control.message.get_bootloader_control().unwrap();
+        assert_eq!(control.slot_info[1].priority(), MAX_SLOT_PRIORITY);
+        assert_eq!(control.slot_info[1].tries_remaining(), MAX_TRIES);
+        assert_eq!(control.slot_info[1].successful_boot(), 0);
+        assert_eq!(control.slot_info[0].priority(), MAX_SLOT_PRIORITY - 1);
+        assert_eq!(control.slot_info[0].successful_boot(), 1);
+
+        // making the running slot active again cancels the update
+        boot_control.set_active_slot(0).unwrap();
+        let control = boot_control.message.get_bootloader_control().unwrap();
+        assert_eq!(control.slot_info[0].priority(), MAX_SLOT_PRIORITY);
+        assert_eq!(control.slot_info[0].successful_boot(), 1);
+        assert_eq!(control.slot_info[1].priority(), MAX_SLOT_PRIORITY - 1);
+    }
+
+    #[test]
+    fn set_active_slot_clears_verity_corruption() {
+        let mut boot_control = boot_control_from_testdata();
+        boot_control.current_slot.set(Some((0, SlotSource::Misc)));
+
+        boot_control.set_slot_verity_corrupted(1).unwrap();
+        boot_control.set_slot_verity_corrupted(0).unwrap();
+        boot_control.set_active_slot(0).unwrap();
+        assert!(boot_control.is_slot_verity_corrupted(0).unwrap());
+
+        boot_control.set_active_slot(1).unwrap();
+        assert!(!boot_control.is_slot_verity_corrupted(1).unwrap());
+        assert!(boot_control.is_bootable(1).unwrap());
+    }
+}
@@ -4,10 +4,34 @@ use thiserror::Error;
 pub enum BootloaderMessageError {
     #[error("Priority out of range")]
     PriorityOutOfRange,
+    #[error("Tries out of range")]
+    TriesOutOfRange,
     #[error("CRC Error")]
     CrcFailure,
     #[error("Insufficient bytes")]
     InsufficientBytes,
     #[error("Data too long")]
     DataTooLong,
+    #[error("Invalid string")]
+    InvalidString,
+    #[error("No bootable slot")]
+    NoBootableSlot,
+    #[error("Invalid magic number")]
+    InvalidMagic,
+    #[error("Unsupported version")]
+    UnsupportedVersion,
+    #[error("Invalid number of slots")]
+    InvalidSlotCount,
+    #[error("Invalid slot index")]
+    InvalidSlotIndex,
+    #[error("No recovery tries remaining")]
+    RecoveryTriesExhausted,
+    #[error("Invalid merge status")]
+    InvalidMergeStatus,
+    #[error("Snapshot merge in progress")]
+    SnapshotMergeInProgress,
+    #[error("Invalid rollback index location")]
+    InvalidRollbackIndexLocation,
+    #[error("Rollback index is lower than the stored one")]
+    RollbackIndexTooLow,
 }
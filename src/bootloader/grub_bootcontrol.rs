@@ -0,0 +1,360 @@
+/*
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+/*
+  Boot control for targets booting with GRUB. The slot metadata of the misc
+  partition is kept in GRUB variables instead, so that grub.cfg can select the
+  slot with the same A/B rules as the Android bootloaders:
+
+  SLOT_COUNT                number of slots
+  SLOT_SUFFIX               suffix of the active slot, "a" to "d"
+  SLOT_x_PRIORITY           priority of slot x, 0 to 15
+  SLOT_x_TRIES              tries remaining for slot x, 0 to 7
+  SLOT_x_SUCCESSFUL         1 if slot x has booted successfully
+  SLOT_x_VERITY_CORRUPTED   1 if dm-verity found slot x corrupted
+*/
+
+use std::{
+    convert::TryFrom,
+    io::{Error, ErrorKind},
+    path::Path,
+};
+
+use sabaton_hal::bootloader::{get_slot_suffix_from_cmd_line, BootControl};
+
+use super::bootcontrol::{slot_index_from_suffix, slot_suffix_from_index, VerifiedBootControl};
+use super::grubenv::{GrubEnvironment, GRUBENV_LOCATION};
+use super::message::{BootloaderControl, MAX_SLOT_PRIORITY, MAX_TRIES};
+use super::storage::{FileStorage, MiscStorage};
+
+pub const SLOT_COUNT_VARIABLE: &str = "SLOT_COUNT";
+pub const SLOT_SUFFIX_VARIABLE: &str = "SLOT_SUFFIX";
+
+pub struct GrubBootControl {
+    env: GrubEnvironment,
+    storage: Box<dyn MiscStorage>,
+    /// Overrides the slot from the kernel command line
+    current_slot: Option<usize>,
+}
+
+impl GrubBootControl {
+    /// Create the boot control over /boot/grub/grubenv
+    pub fn create() -> Result<Self, std::io::Error> {
+        Self::create_from_storage(Box::new(FileStorage::open(Path::new(GRUBENV_LOCATION))?))
+    }
+
+    /// Create the boot control over any storage holding the environment block,
+    /// for example a grubenv file on the host
+    pub fn create_from_storage(storage: Box<dyn MiscStorage>) -> Result<Self, std::io::Error> {
+        let env = GrubEnvironment::load(storage.as_ref())?;
+        Ok(Self {
+            env,
+            storage,
+            current_slot: None,
+        })
+    }
+
+    /// Set the slot that is running, for tools that work on the environment
+    /// of another system. The kernel command line is used otherwise.
+    pub fn set_current_slot(&mut self, slot_index: usize) {
+        self.current_slot = Some(slot_index);
+    }
+
+    /// Write fresh slot variables for the number of slots. All slots get
+    /// the default priority and tries and the first slot is made active.
+    pub fn initialize_metadata(&mut self, number_of_slots: usize) -> Result<(), std::io::Error> {
+        let nb_slot = u8::try_from(number_of_slots).map_err(|_e| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid number of slots : {}", number_of_slots),
+            )
+        })?;
+        let bl_control = BootloaderControl::new(nb_slot)
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e))?;
+        self.save(&bl_control)
+    }
+
+    /// The environment that holds the slot variables
+    pub fn environment(&self) -> &GrubEnvironment {
+        &self.env
+    }
+
+    /// Read the slot variables into the structure the misc partition uses
+    pub fn bootloader_control(&self) -> Result<BootloaderControl, std::io::Error> {
+        let nb_slot = self.get_number(SLOT_COUNT_VARIABLE, 4)?;
+        let mut bl_control = BootloaderControl::new(nb_slot)
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+
+        let suffix = self.env.get(SLOT_SUFFIX_VARIABLE).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{} is not set", SLOT_SUFFIX_VARIABLE),
+            )
+        })?;
+        slot_index_from_suffix(suffix)?;
+        bl_control
+            .set_slot_suffix(suffix)
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+
+        for slot_index in 0..bl_control.slot_count() {
+            let suffix = slot_suffix_from_index(slot_index)?;
+            let slot = &mut bl_control.slot_info[slot_index];
+            slot.set_priority(
+                self.get_number(&slot_variable(suffix, "PRIORITY"), MAX_SLOT_PRIORITY)?,
+            );
+            slot.set_tries_remaining(self.get_number(&slot_variable(suffix, "TRIES"), MAX_TRIES)?);
+            slot.set_successful_boot(self.get_number(&slot_variable(suffix, "SUCCESSFUL"), 1)?);
+            // older environments do not have the flag
+            let verity_corrupted = slot_variable(suffix, "VERITY_CORRUPTED");
+            if self.env.get(&verity_corrupted).is_some() {
+                slot.set_verity_corrupted(self.get_number(&verity_corrupted, 1)?);
+            }
+        }
+
+        Ok(bl_control)
+    }
+
+    /// Store the slot metadata into the variables and write the environment block
+    fn save(&mut self, bl_control: &BootloaderControl) -> Result<(), std::io::Error> {
+        self.env
+            .set(SLOT_COUNT_VARIABLE, &bl_control.slot_count().to_string())?;
+        let suffix = bl_control
+            .slot_suffix()
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?
+            .to_str()
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+        self.env.set(SLOT_SUFFIX_VARIABLE, suffix)?;
+
+        for slot_index in 0..bl_control.slot_count() {
+            let suffix = slot_suffix_from_index(slot_index)?;
+            let slot = &bl_control.slot_info[slot_index];
+            self.env.set(
+                &slot_variable(suffix, "PRIORITY"),
+                &slot.priority().to_string(),
+            )?;
+            self.env.set(
+                &slot_variable(suffix, "TRIES"),
+                &slot.tries_remaining().to_string(),
+            )?;
+            self.env.set(
+                &slot_variable(suffix, "SUCCESSFUL"),
+                &slot.successful_boot().to_string(),
+            )?;
+            self.env.set(
+                &slot_variable(suffix, "VERITY_CORRUPTED"),
+                &slot.verity_corrupted().to_string(),
+            )?;
+        }
+
+        self.env.save(self.storage.as_mut())
+    }
+
+    fn get_number(&self, name: &str, max: u8) -> Result<u8, std::io::Error> {
+        let value = self
+            .env
+            .get(name)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("{} is not set", name)))?;
+        match value.parse::<u8>() {
+            Ok(number) if number <= max => Ok(number),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid value {}={}", name, value),
+            )),
+        }
+    }
+
+    fn check_slot_index(
+        bl_control: &BootloaderControl,
+        slot_index: usize,
+    ) -> Result<(), std::io::Error> {
+        if slot_index < bl_control.slot_count() {
+            Ok(())
+        } else {
+            Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid slot index : {}", slot_index),
+            ))
+        }
+    }
+}
+
+fn slot_variable(suffix: &str, field: &str) -> String {
+    format!("SLOT_{}_{}", suffix, field)
+}
+
+impl BootControl for GrubBootControl {
+    fn number_of_slots(&self) -> Result<usize, std::io::Error> {
+        Ok(self.bootloader_control()?.slot_count())
+    }
+
+    /// Get the current slot from the kernel command line
+    fn current_slot(&self) -> Result<usize, std::io::Error> {
+        if let Some(slot_index) = self.current_slot {
+            return Ok(slot_index);
+        }
+        let command_line = std::fs::read_to_string("/proc/cmdline")?;
+        slot_index_from_suffix(get_slot_suffix_from_cmd_line(&command_line)?)
+    }
+
+    fn set_boot_successful(&mut self) -> Result<(), std::io::Error> {
+        let current_slot = self.current_slot()?;
+        let mut bl_control = self.bootloader_control()?;
+        Self::check_slot_index(&bl_control, current_slot)?;
+
+        bl_control.slot_info[current_slot].set_successful_boot(1);
+        self.save(&bl_control)
+    }
+
+    /// Make the slot active with the highest priority and a full count of tries
+    fn set_active_slot(&mut self, slot_index: usize) -> Result<(), std::io::Error> {
+        let mut bl_control = self.bootloader_control()?;
+        Self::check_slot_index(&bl_control, slot_index)?;
+
+        // the running slot must be known, it keeps its successful boot flag
+        let is_current_slot = self.current_slot()? == slot_index;
+        bl_control
+            .set_active_slot(slot_index, is_current_slot)
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+        self.save(&bl_control)
+    }
+
+    fn set_slot_as_unbootable(&mut self, slot_index: usize) -> Result<(), std::io::Error> {
+        let mut bl_control = self.bootloader_control()?;
+        Self::check_slot_index(&bl_control, slot_index)?;
+
+        bl_control
+            .set_slot_unbootable(slot_index)
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+        self.save(&bl_control)
+    }
+
+    fn is_bootable(&self, slot_index: usize) -> Result<bool, std::io::Error> {
+        let bl_control = self.bootloader_control()?;
+        Self::check_slot_index(&bl_control, slot_index)?;
+        Ok(bl_control.slot_info[slot_index].is_bootable())
+    }
+
+    fn is_slot_successful(&self, slot_index: usize) -> Result<bool, std::io::Error> {
+        let bl_control = self.bootloader_control()?;
+        Self::check_slot_index(&bl_control, slot_index)?;
+        Ok(bl_control.slot_info[slot_index].successful_boot() == 1)
+    }
+
+    fn active_slot(&self) -> Result<usize, std::io::Error> {
+        slot_index_from_suffix(self.env.get(SLOT_SUFFIX_VARIABLE).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{} is not set", SLOT_SUFFIX_VARIABLE),
+            )
+        })?)
+    }
+}
+
+impl VerifiedBootControl for GrubBootControl {
+    fn set_slot_verity_corrupted(&mut self, slot_index: usize) -> Result<(), std::io::Error> {
+        let mut bl_control = self.bootloader_control()?;
+        Self::check_slot_index(&bl_control, slot_index)?;
+
+        bl_control
+            .set_slot_verity_corrupted(slot_index)
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+        self.save(&bl_control)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bootloader::grubenv::GRUBENV_SIZE;
+    use crate::bootloader::storage::{temp_path, MemoryStorage};
+
+    #[test]
+    fn boot_control_over_grubenv_file() {
+        let path = temp_path("grub_boot_control_over_grubenv_file");
+        let mut storage = FileStorage::create(&path, GRUBENV_SIZE as u64).unwrap();
+        GrubEnvironment::new().save(&mut storage).unwrap();
+
+        let mut boot_control = GrubBootControl::create_from_storage(Box::new(storage)).unwrap();
+        assert!(boot_control.number_of_slots().is_err());
+        boot_control.initialize_metadata(2).unwrap();
+        // switching slots needs the running slot
+        let current_slot_unknown = boot_control.current_slot().is_err();
+        assert_eq!(
+            boot_control.set_active_slot(1).is_err(),
+            current_slot_unknown
+        );
+        boot_control.set_current_slot(0);
+        boot_control.set_active_slot(1).unwrap();
+        boot_control.set_slot_as_unbootable(0).unwrap();
+        assert!(boot_control.set_active_slot(2).is_err());
+
+        let storage = FileStorage::open(&path).unwrap();
+        let boot_control = GrubBootControl::create_from_storage(Box::new(storage)).unwrap();
+        assert_eq!(boot_control.number_of_slots().unwrap(), 2);
+        assert_eq!(boot_control.active_slot().unwrap(), 1);
+        assert!(!boot_control.is_bootable(0).unwrap());
+        assert!(boot_control.is_bootable(1).unwrap());
+        assert!(!boot_control.is_slot_successful(1).unwrap());
+        assert_eq!(
+            boot_control.environment().get("SLOT_b_PRIORITY"),
+            Some("15")
+        );
+        assert_eq!(boot_control.environment().get("SLOT_b_TRIES"), Some("7"));
+        assert_eq!(boot_control.environment().get("SLOT_a_PRIORITY"), Some("0"));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn same_slot_selection_as_misc() {
+        let mut storage = MemoryStorage::new(GRUBENV_SIZE);
+        let mut env = GrubEnvironment::new();
+        for (name, value) in [
+            ("SLOT_COUNT", "2"),
+            ("SLOT_SUFFIX", "a"),
+            ("SLOT_a_PRIORITY", "14"),
+            ("SLOT_a_TRIES", "1"),
+            ("SLOT_a_SUCCESSFUL", "1"),
+            ("SLOT_b_PRIORITY", "15"),
+            ("SLOT_b_TRIES", "1"),
+            ("SLOT_b_SUCCESSFUL", "0"),
+        ]
+        .iter()
+        {
+            env.set(name, value).unwrap();
+        }
+        env.save(&mut storage).unwrap();
+
+        let boot_control = GrubBootControl::create_from_storage(Box::new(storage)).unwrap();
+        let mut bl_control = boot_control.bootloader_control().unwrap();
+        assert_eq!(bl_control.select_slot().unwrap(), 1);
+        assert_eq!(bl_control.select_slot().unwrap(), 0);
+    }
+
+    #[test]
+    fn invalid_variables() {
+        let mut storage = MemoryStorage::new(GRUBENV_SIZE);
+        let mut env = GrubEnvironment::new();
+        env.set("SLOT_COUNT", "1").unwrap();
+        env.set("SLOT_SUFFIX", "a").unwrap();
+        env.set("SLOT_a_PRIORITY", "16").unwrap();
+        env.set("SLOT_a_TRIES", "7").unwrap();
+        env.set("SLOT_a_SUCCESSFUL", "0").unwrap();
+        env.save(&mut storage).unwrap();
+
+        let boot_control = GrubBootControl::create_from_storage(Box::new(storage)).unwrap();
+        assert!(boot_control.bootloader_control().is_err());
+        assert!(boot_control.is_bootable(0).is_err());
+    }
+}
@@ -0,0 +1,217 @@
+/*
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+/*
+  The GRUB environment block, compatible with grub-editenv and the load_env and
+  save_env commands.
+
+  The block is exactly 1024 bytes. It starts with a header line, followed by
+  "name=value" lines and is padded with '#' up to its full size. Backslashes
+  and newlines in values are escaped with a backslash. GRUB writes the block
+  in place, so the file must never be truncated or replaced.
+*/
+
+use std::io::{Error, ErrorKind};
+
+use super::storage::MiscStorage;
+
+/// The default location of the environment block
+pub const GRUBENV_LOCATION: &str = "/boot/grub/grubenv";
+/// Size of the environment block
+pub const GRUBENV_SIZE: usize = 1024;
+const GRUBENV_HEADER: &str = "# GRUB Environment Block\n";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GrubEnvironment {
+    /// The variables, in the order they appear in the block
+    vars: Vec<(String, String)>,
+}
+
+impl GrubEnvironment {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read the environment block from the start of the storage
+    pub fn load(storage: &dyn MiscStorage) -> Result<Self, std::io::Error> {
+        let mut buffer = vec![0u8; GRUBENV_SIZE];
+        storage.read_at(&mut buffer, 0)?;
+        Self::from_bytes(&buffer)
+    }
+
+    /// Write the environment block to the start of the storage
+    pub fn save(&self, storage: &mut dyn MiscStorage) -> Result<(), std::io::Error> {
+        storage.write_verified(&self.to_bytes()?, 0)
+    }
+
+    /// Parse an environment block
+    pub fn from_bytes(data: &[u8]) -> Result<Self, std::io::Error> {
+        let contents =
+            std::str::from_utf8(data).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let body = contents
+            .strip_prefix(GRUBENV_HEADER)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Not a GRUB environment block"))?;
+
+        let mut vars: Vec<(String, String)> = Vec::new();
+        let mut chars = body.chars();
+        let mut line = String::new();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => {
+                    // keep the escape, it is resolved once the name is split off
+                    line.push(c);
+                    if let Some(escaped) = chars.next() {
+                        line.push(escaped);
+                    }
+                }
+                '\n' => {
+                    parse_line(&line, &mut vars)?;
+                    line.clear();
+                }
+                _ => line.push(c),
+            }
+        }
+        // the padding has no newline at the end
+        parse_line(&line, &mut vars)?;
+
+        Ok(Self { vars })
+    }
+
+    /// Serialize the environment into a block of GRUBENV_SIZE bytes
+    pub fn to_bytes(&self) -> Result<Vec<u8>, std::io::Error> {
+        let mut contents = String::from(GRUBENV_HEADER);
+        for (name, value) in self.vars.iter() {
+            contents.push_str(name);
+            contents.push('=');
+            for c in value.chars() {
+                if c == '\\' || c == '\n' {
+                    contents.push('\\');
+                }
+                contents.push(c);
+            }
+            contents.push('\n');
+        }
+
+        if contents.len() > GRUBENV_SIZE {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Variables do not fit into the GRUB environment block",
+            ));
+        }
+        let mut data = contents.into_bytes();
+        data.resize(GRUBENV_SIZE, b'#');
+        Ok(data)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.vars
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Set a variable. A new variable is added at the end of the block.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), std::io::Error> {
+        if name.is_empty() || name.contains(&['=', '\n', '\\', '#'][..]) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid variable name : {}", name),
+            ));
+        }
+        match self.vars.iter_mut().find(|(n, _)| n == name) {
+            Some((_, v)) => *v = String::from(value),
+            None => self.vars.push((String::from(name), String::from(value))),
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        let position = self.vars.iter().position(|(n, _)| n == name)?;
+        Some(self.vars.remove(position).1)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.vars.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+}
+
+fn parse_line(line: &str, vars: &mut Vec<(String, String)>) -> Result<(), std::io::Error> {
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(());
+    }
+    let (name, escaped_value) = line.split_once('=').ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid GRUB environment line : {}", line),
+        )
+    })?;
+
+    let mut value = String::with_capacity(escaped_value.len());
+    let mut chars = escaped_value.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(escaped) = chars.next() {
+                value.push(escaped);
+            }
+        } else {
+            value.push(c);
+        }
+    }
+    vars.push((String::from(name), value));
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bootloader::storage::MemoryStorage;
+
+    #[test]
+    fn read_grub_editenv_block() {
+        // as written by grub-editenv grubenv set saved_entry=linux next_entry=
+        let mut data = b"# GRUB Environment Block\nsaved_entry=linux\nnext_entry=\n".to_vec();
+        data.resize(GRUBENV_SIZE, b'#');
+
+        let env = GrubEnvironment::from_bytes(&data).unwrap();
+        assert_eq!(env.get("saved_entry"), Some("linux"));
+        assert_eq!(env.get("next_entry"), Some(""));
+        assert_eq!(env.get("missing"), None);
+        assert_eq!(env.to_bytes().unwrap(), data);
+
+        assert!(GrubEnvironment::from_bytes(&[b'#'; GRUBENV_SIZE]).is_err());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut storage = MemoryStorage::new(GRUBENV_SIZE);
+        let mut env = GrubEnvironment::new();
+        env.set("SLOT_SUFFIX", "b").unwrap();
+        env.set("escaped", "a\\b\nc").unwrap();
+        env.set("SLOT_SUFFIX", "a").unwrap();
+        assert!(env.set("in=valid", "1").is_err());
+        env.save(&mut storage).unwrap();
+
+        assert_eq!(storage.as_slice()[GRUBENV_SIZE - 1], b'#');
+        let loaded = GrubEnvironment::load(&storage).unwrap();
+        assert_eq!(loaded, env);
+        assert_eq!(loaded.get("escaped"), Some("a\\b\nc"));
+        assert_eq!(
+            loaded.iter().map(|(n, _)| n).collect::<Vec<&str>>(),
+            vec!["SLOT_SUFFIX", "escaped"]
+        );
+
+        env.set("too_long", &"x".repeat(GRUBENV_SIZE)).unwrap();
+        assert!(env.to_bytes().is_err());
+    }
+}
@@ -0,0 +1,280 @@
+/*
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+/*
+  A small key-value store in the vendor space of the misc partition. It is
+  meant for per-device boot flags that must survive a wipe of /data and must
+  be readable from the initrd before any filesystem is mounted.
+
+  Layout (all integers are little endian):
+
+  0   magic        "SKVS"
+  4   version      u8
+  5   reserved     [u8; 3]
+  8   payload_len  u32
+  12  crc32        u32, CRC32 of the payload
+  16  payload      entries, each one being
+                     key_len   u8
+                     key       [u8; key_len], UTF-8
+                     value_len u16
+                     value     [u8; value_len]
+*/
+
+use std::{collections::BTreeMap, convert::TryFrom};
+
+use crc::{Crc, CRC_32_ISO_HDLC};
+
+use super::error::BootloaderMessageError;
+use super::storage::MiscStorage;
+
+/// The store starts right after the BootloaderMessageAB structure
+pub const KEY_VALUE_STORE_OFFSET_IN_MISC: usize = 4 * 1024;
+/// Space reserved for the store, including the header
+pub const KEY_VALUE_STORE_SIZE: usize = 4 * 1024;
+
+const KEY_VALUE_STORE_MAGIC: [u8; 4] = *b"SKVS";
+const KEY_VALUE_STORE_VERSION: u8 = 1;
+const HEADER_SIZE: usize = 16;
+
+/// Well known key for the factory mode flag
+pub const KEY_FACTORY_MODE: &str = "factory_mode";
+/// Well known key for the reason of the last reboot
+pub const KEY_LAST_REBOOT_REASON: &str = "last_reboot_reason";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MiscKeyValueStore {
+    entries: BTreeMap<String, Vec<u8>>,
+}
+
+impl MiscKeyValueStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read the store from the misc storage.
+    pub fn load(storage: &dyn MiscStorage) -> Result<Self, std::io::Error> {
+        let mut buffer = vec![0u8; KEY_VALUE_STORE_SIZE];
+        storage.read_at(&mut buffer, KEY_VALUE_STORE_OFFSET_IN_MISC as u64)?;
+        Self::from_bytes(&buffer)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    /// Write the store into the misc storage
+    pub fn save(&self, storage: &mut dyn MiscStorage) -> Result<(), std::io::Error> {
+        let buffer = self
+            .to_bytes()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        storage.write_verified(&buffer, KEY_VALUE_STORE_OFFSET_IN_MISC as u64)
+    }
+
+    /// Parse the store from its serialized form
+    pub fn from_bytes(data: &[u8]) -> Result<Self, BootloaderMessageError> {
+        if data.len() < HEADER_SIZE {
+            return Err(BootloaderMessageError::InsufficientBytes);
+        }
+        if data[0..4] != KEY_VALUE_STORE_MAGIC {
+            return Err(BootloaderMessageError::InvalidMagic);
+        }
+        if data[4] != KEY_VALUE_STORE_VERSION {
+            return Err(BootloaderMessageError::UnsupportedVersion);
+        }
+
+        let payload_len = read_u32(&data[8..12]) as usize;
+        let crc32 = read_u32(&data[12..16]);
+        let payload = data
+            .get(HEADER_SIZE..HEADER_SIZE.saturating_add(payload_len))
+            .ok_or(BootloaderMessageError::InsufficientBytes)?;
+
+        let algo = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+        if algo.checksum(payload) != crc32 {
+            return Err(BootloaderMessageError::CrcFailure);
+        }
+
+        let mut entries = BTreeMap::new();
+        let mut rest = payload;
+        while !rest.is_empty() {
+            let key_len = rest[0] as usize;
+            let key = rest
+                .get(1..1 + key_len)
+                .ok_or(BootloaderMessageError::InsufficientBytes)?;
+            let key =
+                std::str::from_utf8(key).map_err(|_e| BootloaderMessageError::InvalidString)?;
+            rest = &rest[1 + key_len..];
+
+            let value_len = rest
+                .get(0..2)
+                .map(|b| u16::from_le_bytes([b[0], b[1]]) as usize)
+                .ok_or(BootloaderMessageError::InsufficientBytes)?;
+            let value = rest
+                .get(2..2 + value_len)
+                .ok_or(BootloaderMessageError::InsufficientBytes)?;
+            rest = &rest[2 + value_len..];
+
+            entries.insert(String::from(key), value.to_vec());
+        }
+
+        Ok(Self { entries })
+    }
+
+    /// Serialize the store. The result is padded to the full size of the store.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, BootloaderMessageError> {
+        let mut payload = Vec::new();
+        for (key, value) in self.entries.iter() {
+            payload.push(key.len() as u8);
+            payload.extend_from_slice(key.as_bytes());
+            payload.extend_from_slice(&(value.len() as u16).to_le_bytes());
+            payload.extend_from_slice(value);
+        }
+
+        if HEADER_SIZE + payload.len() > KEY_VALUE_STORE_SIZE {
+            return Err(BootloaderMessageError::DataTooLong);
+        }
+
+        let algo = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+        let mut data = Vec::with_capacity(KEY_VALUE_STORE_SIZE);
+        data.extend_from_slice(&KEY_VALUE_STORE_MAGIC);
+        data.extend_from_slice(&[KEY_VALUE_STORE_VERSION, 0, 0, 0]);
+        data.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        data.extend_from_slice(&algo.checksum(&payload).to_le_bytes());
+        data.extend_from_slice(&payload);
+        data.resize(KEY_VALUE_STORE_SIZE, 0);
+        Ok(data)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&[u8]> {
+        self.entries.get(key).map(|v| v.as_slice())
+    }
+
+    /// Get a value that was stored as a string
+    pub fn get_str(&self, key: &str) -> Result<Option<&str>, BootloaderMessageError> {
+        self.get(key)
+            .map(|v| std::str::from_utf8(v).map_err(|_e| BootloaderMessageError::InvalidString))
+            .transpose()
+    }
+
+    /// Set the value for a key. Keys can be up to 255 bytes long, values up to 64K,
+    /// but all entries together must fit into the store.
+    pub fn set(&mut self, key: &str, value: &[u8]) -> Result<(), BootloaderMessageError> {
+        if key.is_empty() {
+            return Err(BootloaderMessageError::InvalidString);
+        }
+        u8::try_from(key.len()).map_err(|_e| BootloaderMessageError::DataTooLong)?;
+        u16::try_from(value.len()).map_err(|_e| BootloaderMessageError::DataTooLong)?;
+
+        let previous = self.entries.insert(String::from(key), value.to_vec());
+        if self.payload_size() + HEADER_SIZE > KEY_VALUE_STORE_SIZE {
+            // restore the store as it was
+            match previous {
+                Some(previous) => self.entries.insert(String::from(key), previous),
+                None => self.entries.remove(key),
+            };
+            return Err(BootloaderMessageError::DataTooLong);
+        }
+        Ok(())
+    }
+
+    pub fn set_str(&mut self, key: &str, value: &str) -> Result<(), BootloaderMessageError> {
+        self.set(key, value.as_bytes())
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Vec<u8>> {
+        self.entries.remove(key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_slice()))
+    }
+
+    fn payload_size(&self) -> usize {
+        self.entries
+            .iter()
+            .map(|(k, v)| 1 + k.len() + 2 + v.len())
+            .sum()
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bootloader::storage::MemoryStorage;
+
+    #[test]
+    fn save_and_load() {
+        let mut storage = MemoryStorage::new(64 * 1024);
+        assert!(MiscKeyValueStore::load(&storage).is_err());
+
+        let mut store = MiscKeyValueStore::new();
+        store.set_str(KEY_FACTORY_MODE, "1").unwrap();
+        store.set_str(KEY_LAST_REBOOT_REASON, "watchdog").unwrap();
+        store.set("binary", &[0, 1, 2, 255]).unwrap();
+        store.save(&mut storage).unwrap();
+
+        // the bootloader message is left alone
+        assert_eq!(&storage.as_slice()[0..4096], &[0u8; 4096][..]);
+
+        let mut loaded = MiscKeyValueStore::load(&storage).unwrap();
+        assert_eq!(loaded, store);
+        assert_eq!(loaded.get_str(KEY_FACTORY_MODE).unwrap(), Some("1"));
+        assert_eq!(
+            loaded.get_str(KEY_LAST_REBOOT_REASON).unwrap(),
+            Some("watchdog")
+        );
+        assert_eq!(loaded.get("binary"), Some(&[0u8, 1, 2, 255][..]));
+        assert_eq!(loaded.get("missing"), None);
+
+        assert_eq!(loaded.remove(KEY_FACTORY_MODE), Some(b"1".to_vec()));
+        loaded.save(&mut storage).unwrap();
+        let loaded = MiscKeyValueStore::load(&storage).unwrap();
+        assert_eq!(loaded.get(KEY_FACTORY_MODE), None);
+        assert_eq!(loaded.iter().count(), 2);
+    }
+
+    #[test]
+    fn detect_corruption() {
+        let mut store = MiscKeyValueStore::new();
+        store
+            .set_str(KEY_LAST_REBOOT_REASON, "kernel_panic")
+            .unwrap();
+        let mut data = store.to_bytes().unwrap();
+        data[HEADER_SIZE + 3] ^= 0x01;
+
+        assert!(matches!(
+            MiscKeyValueStore::from_bytes(&data),
+            Err(BootloaderMessageError::CrcFailure)
+        ));
+    }
+
+    #[test]
+    fn store_is_bounded() {
+        let mut store = MiscKeyValueStore::new();
+        let value = vec![0xa5u8; 2048];
+        store.set("first", &value).unwrap();
+        assert!(matches!(
+            store.set("second", &value),
+            Err(BootloaderMessageError::DataTooLong)
+        ));
+        assert_eq!(store.get("second"), None);
+        assert!(store.to_bytes().is_ok());
+
+        let long_key = "k".repeat(256);
+        assert!(matches!(
+            store.set(&long_key, b"v"),
+            Err(BootloaderMessageError::DataTooLong)
+        ));
+    }
+}
@@ -21,24 +21,14 @@
   allows us to use the implementation from UBoot.
 */
 
+use std::{convert::TryFrom, ffi::CStr, fmt::Display, str::FromStr};
 
-use std::{
-    convert::TryFrom,
-    ffi::{CStr, CString},
-    fmt::Display,
-    io::Write,
-    mem::MaybeUninit,
-    os::unix::prelude::FileExt,
-};
-
-use crate::{
-    mount::early_partitions::{ensure_mount_device_is_created, MISC_PARTITION_NAME},
-    uevent::create_and_bind_netlink_socket,
-};
-
+use super::bootcontrol::slot_suffix_from_index;
 use super::error::BootloaderMessageError;
+use super::storage::{BlockDeviceStorage, MiscStorage};
 use c2rust_bitfields::BitfieldStruct;
 use crc::{Crc, CRC_32_ISO_HDLC};
+use serde::{de::Error as _, ser::Error as _, Deserialize, Deserializer, Serialize, Serializer};
 
 /// Spaces used by misc partition are as below:
 /// 0   - 2K     For bootloader_message
@@ -47,8 +37,33 @@ use crc::{Crc, CRC_32_ISO_HDLC};
 /// 16K - 64K    Used by uncrypt and recovery to store wipe_package for A/B devices
 /// Note that these offsets are admitted by bootloader,recovery and uncrypt, so they
 /// are not configurable without changing all of them.
+///
+/// The vendor space is used as below:
+/// 2K  - 4K     bootloader_message_ab
+/// 4K  - 8K     Key-value store for boot flags (see kvstore.rs)
+/// 8K  - 12K    Backup copy of bootloader_message_ab
+/// 12K - 12K+64 misc_virtual_ab_message
+/// 12K+512 - 12K+768 Anti-rollback indexes (see rollback_index.rs)
 pub const BOOTLOADER_MESSAGE_OFFSET_IN_MISC: usize = 0usize;
 pub const VENDOR_SPACE_OFFSET_IN_MISC: usize = 2 * 1024usize;
+pub const BACKUP_BOOTLOADER_MESSAGE_OFFSET_IN_MISC: usize = 8 * 1024usize;
+pub const VIRTUAL_AB_MESSAGE_OFFSET_IN_MISC: usize = 12 * 1024usize;
+pub const WIPE_PACKAGE_OFFSET_IN_MISC: usize = 16 * 1024usize;
+pub const WIPE_PACKAGE_END_IN_MISC: usize = 64 * 1024usize;
+
+/// Size of the serialized BootloaderMessage
+pub const BOOTLOADER_MESSAGE_SIZE: usize = 2048;
+/// Size of the serialized BootloaderMessageAB
+pub const BOOTLOADER_MESSAGE_AB_SIZE: usize = 4096;
+/// Size of the serialized BootloaderControl
+pub const BOOTLOADER_CONTROL_SIZE: usize = 32;
+
+/// The sequence number of a copy of the BootloaderMessageAB is kept at the
+/// start of its reserved space, which the bootloader does not touch:
+/// magic "SBSQ", the sequence number as u64 and the CRC32 of both (little endian).
+const SEQUENCE_OFFSET_IN_RESERVED: usize = 0;
+const SEQUENCE_MAGIC: [u8; 4] = *b"SBSQ";
+const SEQUENCE_SIZE: usize = 16;
 
 /// Bootloader Message (2-KiB)
 ///
@@ -98,6 +113,209 @@ pub struct BootloaderMessage {
     reserved: [u8; 1184],
 }
 
+/// Commands that can be placed into the command field of the
+/// bootloader message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BootloaderCommand {
+    /// Boot into recovery. The recovery field carries the arguments.
+    BootRecovery,
+    /// Stop in the bootloader (fastboot) on the next boot only.
+    BootonceBootloader,
+    /// Boot into userspace fastboot.
+    BootFastboot,
+    /// Other commands
+    Other(String),
+}
+
+impl BootloaderCommand {
+    pub fn as_str(&self) -> &str {
+        match self {
+            BootloaderCommand::BootRecovery => "boot-recovery",
+            BootloaderCommand::BootonceBootloader => "bootonce-bootloader",
+            BootloaderCommand::BootFastboot => "boot-fastboot",
+            BootloaderCommand::Other(s) => s.as_str(),
+        }
+    }
+}
+
+impl FromStr for BootloaderCommand {
+    type Err = BootloaderMessageError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "boot-recovery" => Ok(BootloaderCommand::BootRecovery),
+            "bootonce-bootloader" => Ok(BootloaderCommand::BootonceBootloader),
+            "boot-fastboot" => Ok(BootloaderCommand::BootFastboot),
+            _ => Ok(BootloaderCommand::Other(String::from(s))),
+        }
+    }
+}
+
+impl Display for BootloaderCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Read a NUL terminated string from a fixed size field. A field without
+/// a NUL byte is read up to its full length.
+fn field_to_str(field: &[u8]) -> Result<&str, BootloaderMessageError> {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    std::str::from_utf8(&field[0..end]).map_err(|_e| BootloaderMessageError::InvalidString)
+}
+
+/// Write a string into a fixed size field. A shorter string is NUL terminated,
+/// a string of the full length is stored without the NUL byte, the same way
+/// field_to_str reads it. The rest of the field is cleared.
+fn str_to_field(field: &mut [u8], value: &str) -> Result<(), BootloaderMessageError> {
+    let bytes = value.as_bytes();
+    if bytes.len() > field.len() {
+        Err(BootloaderMessageError::DataTooLong)
+    } else if bytes.contains(&0) {
+        Err(BootloaderMessageError::InvalidString)
+    } else {
+        field.fill(0);
+        field[0..bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+/// Reserved bytes as they are exported to JSON. Trailing zeros are left out,
+/// so that a cleared field is exported as an empty list.
+fn reserved_to_vec(field: &[u8]) -> Vec<u8> {
+    let end = field.iter().rposition(|b| *b != 0).map_or(0, |p| p + 1);
+    field[0..end].to_vec()
+}
+
+/// Restore reserved bytes imported from JSON. The rest of the field is cleared.
+fn vec_to_reserved(field: &mut [u8], bytes: &[u8]) -> Result<(), BootloaderMessageError> {
+    if bytes.len() > field.len() {
+        Err(BootloaderMessageError::DataTooLong)
+    } else {
+        field.fill(0);
+        field[0..bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+/// Copy a fixed size field out of the data. The caller checks the length of the data.
+fn read_array<const N: usize>(data: &[u8], offset: usize) -> [u8; N] {
+    let mut field = [0u8; N];
+    field.copy_from_slice(&data[offset..offset + N]);
+    field
+}
+
+impl BootloaderMessage {
+    /// Parse the message from the first BOOTLOADER_MESSAGE_SIZE bytes of the data
+    pub fn from_bytes(data: &[u8]) -> Result<Self, BootloaderMessageError> {
+        if data.len() < BOOTLOADER_MESSAGE_SIZE {
+            return Err(BootloaderMessageError::InsufficientBytes);
+        }
+        Ok(BootloaderMessage {
+            command: read_array(data, 0),
+            status: read_array(data, 32),
+            recovery: read_array(data, 64),
+            stage: read_array(data, 832),
+            reserved: read_array(data, 864),
+        })
+    }
+
+    /// Serialize the message into BOOTLOADER_MESSAGE_SIZE bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = vec![0u8; BOOTLOADER_MESSAGE_SIZE];
+        data[0..32].copy_from_slice(&self.command);
+        data[32..64].copy_from_slice(&self.status);
+        data[64..832].copy_from_slice(&self.recovery);
+        data[832..864].copy_from_slice(&self.stage);
+        data[864..2048].copy_from_slice(&self.reserved);
+        data
+    }
+
+    /// Get the command for the bootloader. None if no command is set.
+    pub fn command(&self) -> Result<Option<BootloaderCommand>, BootloaderMessageError> {
+        let command = field_to_str(&self.command)?;
+        if command.is_empty() {
+            Ok(None)
+        } else {
+            command.parse().map(Some)
+        }
+    }
+
+    pub fn set_command(
+        &mut self,
+        command: &BootloaderCommand,
+    ) -> Result<(), BootloaderMessageError> {
+        str_to_field(&mut self.command, command.as_str())
+    }
+
+    pub fn clear_command(&mut self) {
+        self.command.fill(0);
+    }
+
+    /// The status field. Only used by the deprecated radio and hboot updates.
+    pub fn status(&self) -> Result<&str, BootloaderMessageError> {
+        field_to_str(&self.status)
+    }
+
+    /// Get the arguments passed to recovery. The recovery field is of the
+    /// form "recovery\n--wipe_data\n--locale=en_US\n". The leading "recovery"
+    /// line is not part of the returned arguments.
+    pub fn recovery_args(&self) -> Result<Vec<String>, BootloaderMessageError> {
+        let recovery = field_to_str(&self.recovery)?;
+        let mut lines = recovery.lines();
+        match lines.next() {
+            Some("recovery") => Ok(lines.filter(|l| !l.is_empty()).map(String::from).collect()),
+            Some("") | None => Ok(Vec::new()),
+            Some(_) => Err(BootloaderMessageError::InvalidString),
+        }
+    }
+
+    /// Set the arguments passed to recovery. Each argument is placed on its own line.
+    pub fn set_recovery_args(&mut self, args: &[&str]) -> Result<(), BootloaderMessageError> {
+        let mut recovery = String::from("recovery\n");
+        for arg in args {
+            if arg.contains('\n') {
+                return Err(BootloaderMessageError::InvalidString);
+            }
+            recovery.push_str(arg);
+            recovery.push('\n');
+        }
+        str_to_field(&mut self.recovery, &recovery)
+    }
+
+    pub fn clear_recovery_args(&mut self) {
+        self.recovery.fill(0);
+    }
+
+    /// The stage string for multistage packages. Of the form "#/#", eg "1/3".
+    pub fn stage(&self) -> Result<&str, BootloaderMessageError> {
+        field_to_str(&self.stage)
+    }
+
+    pub fn set_stage(&mut self, stage: &str) -> Result<(), BootloaderMessageError> {
+        str_to_field(&mut self.stage, stage)
+    }
+
+    pub fn clear_stage(&mut self) {
+        self.stage.fill(0);
+    }
+
+    /// Request a boot into recovery with the provided arguments.
+    pub fn set_boot_recovery(&mut self, args: &[&str]) -> Result<(), BootloaderMessageError> {
+        self.set_recovery_args(args)?;
+        self.set_command(&BootloaderCommand::BootRecovery)
+    }
+
+    /// Clear the whole message. This is what recovery does once it is done.
+    pub fn clear(&mut self) {
+        self.command.fill(0);
+        self.status.fill(0);
+        self.recovery.fill(0);
+        self.stage.fill(0);
+        self.reserved.fill(0);
+    }
+}
+
 /**
  * We must be cautious when changing the bootloader_message struct size,
  * because A/B-specific fields may end up with different offsets.
@@ -128,133 +346,342 @@ pub struct BootloaderMessage {
 pub struct BootloaderMessageAB {
     pub message: BootloaderMessage,
     pub slot_suffix: [u8; 32],
-    pub update_channel: [u8; 128],
+    update_channel: [u8; 128],
     // Round up the entire struct to 4096-byte.
     reserved: [u8; 1888],
 }
 
 impl BootloaderMessageAB {
-    pub fn get_bootloader_control(&self) -> Result<&BootloaderControl, BootloaderMessageError> {
-        let crc32: u32 = u32::from_ne_bytes([
-            self.slot_suffix[28],
-            self.slot_suffix[29],
-            self.slot_suffix[30],
-            self.slot_suffix[31],
-        ]);
-        let data = &self.slot_suffix[0..28];
-        let algo = Crc::<u32>::new(&CRC_32_ISO_HDLC);
-        let computed_checksum = algo.checksum(data);
-
-        if crc32 != computed_checksum {
-            Err(BootloaderMessageError::CrcFailure)
-        } else {
-            let bolo_ctrl_ptr = data.as_ptr() as *const BootloaderControl;
-            let bolo_message_ab = unsafe { bolo_ctrl_ptr.as_ref().unwrap() };
-            Ok(bolo_message_ab)
+    /// Parse the message from the first BOOTLOADER_MESSAGE_AB_SIZE bytes of the data
+    pub fn from_bytes(data: &[u8]) -> Result<Self, BootloaderMessageError> {
+        if data.len() < BOOTLOADER_MESSAGE_AB_SIZE {
+            return Err(BootloaderMessageError::InsufficientBytes);
         }
+        Ok(BootloaderMessageAB {
+            message: BootloaderMessage::from_bytes(&data[0..BOOTLOADER_MESSAGE_SIZE])?,
+            slot_suffix: read_array(data, 2048),
+            update_channel: read_array(data, 2080),
+            reserved: read_array(data, 2208),
+        })
     }
 
-    pub fn get_bootloader_control_mut(
-        &mut self,
-    ) -> Result<&mut BootloaderControl, BootloaderMessageError> {
-        let crc32: u32 = u32::from_ne_bytes([
-            self.slot_suffix[28],
-            self.slot_suffix[29],
-            self.slot_suffix[30],
-            self.slot_suffix[31],
-        ]);
-        let data = &self.slot_suffix[0..28];
-        let algo = Crc::<u32>::new(&CRC_32_ISO_HDLC);
-        let computed_checksum = algo.checksum(data);
+    /// Serialize the message into BOOTLOADER_MESSAGE_AB_SIZE bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = vec![0u8; BOOTLOADER_MESSAGE_AB_SIZE];
+        data[0..BOOTLOADER_MESSAGE_SIZE].copy_from_slice(&self.message.to_bytes());
+        data[2048..2080].copy_from_slice(&self.slot_suffix);
+        data[2080..2208].copy_from_slice(&self.update_channel);
+        data[2208..4096].copy_from_slice(&self.reserved);
+        data
+    }
 
-        if crc32 != computed_checksum {
-            Err(BootloaderMessageError::CrcFailure)
-        } else {
-            let bolo_ctrl_ptr = data.as_ptr() as *mut BootloaderControl;
-            let bolo_message_ab = unsafe { bolo_ctrl_ptr.as_mut().unwrap() };
-            Ok(bolo_message_ab)
+    /// The update channel, eg "stable" or "beta". Empty if no channel is set.
+    pub fn update_channel(&self) -> Result<&str, BootloaderMessageError> {
+        field_to_str(&self.update_channel)
+    }
+
+    /// Set the update channel. It can be up to 128 bytes long and must not
+    /// contain a NUL byte.
+    pub fn set_update_channel(&mut self, channel: &str) -> Result<(), BootloaderMessageError> {
+        str_to_field(&mut self.update_channel, channel)
+    }
+
+    pub fn clear_update_channel(&mut self) {
+        self.update_channel.fill(0);
+    }
+
+    /// The sequence number of this copy of the message. It is increased every time
+    /// the primary and backup copies are saved, so that the newest valid copy can
+    /// be found. A copy without a valid sequence number reads as 0.
+    pub fn sequence(&self) -> u64 {
+        let start = SEQUENCE_OFFSET_IN_RESERVED;
+        let field = &self.reserved[start..start + SEQUENCE_SIZE];
+        let algo = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+        if field[0..4] != SEQUENCE_MAGIC
+            || u32::from_le_bytes(read_array(field, 12)) != algo.checksum(&field[0..12])
+        {
+            return 0;
         }
+        u64::from_le_bytes(read_array(field, 4))
     }
 
-    /// Call this function to recompute the checksum
-    fn set_checksum(&mut self) {
-        let data = &self.slot_suffix[0..28];
+    fn set_sequence(&mut self, sequence: u64) {
+        let start = SEQUENCE_OFFSET_IN_RESERVED;
+        let field = &mut self.reserved[start..start + SEQUENCE_SIZE];
+        field[0..4].copy_from_slice(&SEQUENCE_MAGIC);
+        field[4..12].copy_from_slice(&sequence.to_le_bytes());
         let algo = Crc::<u32>::new(&CRC_32_ISO_HDLC);
-        let computed_checksum = algo.checksum(data).to_ne_bytes();
-        self.slot_suffix[28] = computed_checksum[0];
-        self.slot_suffix[29] = computed_checksum[1];
-        self.slot_suffix[30] = computed_checksum[2];
-        self.slot_suffix[31] = computed_checksum[3];
+        let crc32 = algo.checksum(&field[0..12]);
+        field[12..16].copy_from_slice(&crc32.to_le_bytes());
     }
 
-    /// Retrieve the message as a slice that can be written to the flash
-    pub fn as_slice(&mut self) -> &[u8] {
-        self.set_checksum(); // set the checksum in case the structure was modified
-        let ptr: *const BootloaderMessageAB = self;
-        unsafe {
-            std::slice::from_raw_parts(ptr as *const u8, std::mem::size_of::<BootloaderMessageAB>())
+    /// Get the bootloader control from the slot_suffix field. The CRC, the magic
+    /// number and the version are checked.
+    pub fn get_bootloader_control(&self) -> Result<BootloaderControl, BootloaderMessageError> {
+        let crc32 = u32::from_le_bytes(read_array(&self.slot_suffix, 28));
+        let algo = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+        if crc32 != algo.checksum(&self.slot_suffix[0..28]) {
+            return Err(BootloaderMessageError::CrcFailure);
         }
+
+        let control = BootloaderControl::from_bytes(&self.slot_suffix)?;
+        control.validate()?;
+        Ok(control)
+    }
+
+    /// Replace the bootloader control with a freshly initialized one. Use this
+    /// to bring up a blank misc partition or to repair corrupted metadata.
+    pub fn initialize_bootloader_control(
+        &mut self,
+        nb_slot: u8,
+    ) -> Result<(), BootloaderMessageError> {
+        self.set_bootloader_control(&BootloaderControl::new(nb_slot)?);
+        Ok(())
+    }
+
+    /// Store the bootloader control into the slot_suffix field, with a new CRC
+    pub fn set_bootloader_control(&mut self, control: &BootloaderControl) {
+        self.slot_suffix = control.to_bytes();
     }
 
     /// Read the contents of the MISC partition and create a BootloaderMessageAB structure from it
     pub fn create_from_misc_partition() -> Result<BootloaderMessageAB, std::io::Error> {
-        let mut nl_socket = create_and_bind_netlink_socket()?;
-
-        let misc_partition_name = CString::new(MISC_PARTITION_NAME)?;
-        ensure_mount_device_is_created(&misc_partition_name, &mut nl_socket)?;
-
-        let misc_partition_handle = std::fs::OpenOptions::new()
-            .read(true)
-            .open(MISC_PARTITION_NAME)?;
-
-        // create an uninitialized BootloaderMessageAB structure
-        let mut bootloader_message_ab: MaybeUninit<BootloaderMessageAB> = MaybeUninit::uninit();
-        let as_ptr = bootloader_message_ab.as_mut_ptr() as *mut u8;
-        let slice = unsafe {
-            std::slice::from_raw_parts_mut(
-                as_ptr as *mut u8,
-                std::mem::size_of::<BootloaderMessageAB>(),
-            )
-        };
-        assert_eq!(slice.len(), 4096);
+        Self::create_from_storage(&BlockDeviceStorage::misc()?)
+    }
+
+    /// Read the first 4KB of the storage and create a BootloaderMessageAB structure from it
+    pub fn create_from_storage(
+        storage: &dyn MiscStorage,
+    ) -> Result<BootloaderMessageAB, std::io::Error> {
+        Self::read_from_storage(storage, BOOTLOADER_MESSAGE_OFFSET_IN_MISC)
+    }
+
+    /// Read the primary copy and the backup copy in the vendor space and create a
+    /// BootloaderMessageAB structure from them. The bootloader control is taken
+    /// from the copy with a valid bootloader control and the highest sequence number.
+    /// The primary copy wins a tie, as the bootloader only updates the primary copy.
+    pub fn create_from_storage_with_backup(
+        storage: &dyn MiscStorage,
+    ) -> Result<BootloaderMessageAB, std::io::Error> {
+        let mut bootloader_message_ab =
+            Self::read_from_storage(storage, BOOTLOADER_MESSAGE_OFFSET_IN_MISC)?;
+        let backup =
+            match Self::read_from_storage(storage, BACKUP_BOOTLOADER_MESSAGE_OFFSET_IN_MISC) {
+                Ok(backup) if backup.get_bootloader_control().is_ok() => backup,
+                _ => {
+                    log::debug!("No valid backup copy of the bootloader control");
+                    return Ok(bootloader_message_ab);
+                }
+            };
+
+        match bootloader_message_ab.get_bootloader_control() {
+            Ok(_) if bootloader_message_ab.sequence() >= backup.sequence() => {}
+            Ok(_) => {
+                log::warn!(
+                    "Backup copy of the bootloader control is newer ({} > {}), using it",
+                    backup.sequence(),
+                    bootloader_message_ab.sequence()
+                );
+                bootloader_message_ab.slot_suffix = backup.slot_suffix;
+                bootloader_message_ab.set_sequence(backup.sequence());
+            }
+            Err(e) => {
+                log::warn!(
+                    "Bootloader control is invalid ({}), using the backup copy",
+                    e
+                );
+                bootloader_message_ab.slot_suffix = backup.slot_suffix;
+                bootloader_message_ab.set_sequence(backup.sequence());
+            }
+        }
+        Ok(bootloader_message_ab)
+    }
 
-        misc_partition_handle.read_exact_at(slice, 0)?;
-        unsafe { Ok(bootloader_message_ab.assume_init()) }
+    fn read_from_storage(
+        storage: &dyn MiscStorage,
+        offset: usize,
+    ) -> Result<BootloaderMessageAB, std::io::Error> {
+        let mut data = vec![0u8; BOOTLOADER_MESSAGE_AB_SIZE];
+        storage.read_at(&mut data, offset as u64)?;
+        Self::from_bytes(&data).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
     }
 
     /// Store the contents into the first 4KB of the Misc Partition
-    pub fn save_to_misc_partition(&mut self) -> Result<(), std::io::Error> {
-        let mut nl_socket = create_and_bind_netlink_socket()?;
-        let misc_partition_name = CString::new(MISC_PARTITION_NAME)?;
-        ensure_mount_device_is_created(&misc_partition_name, &mut nl_socket)?;
-        let mut misc_partition_handle = std::fs::OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .open(MISC_PARTITION_NAME)?;
-        misc_partition_handle.write_all(self.as_slice())
+    pub fn save_to_misc_partition(&self) -> Result<(), std::io::Error> {
+        self.save_to_storage(&mut BlockDeviceStorage::misc()?)
+    }
+
+    /// Store the contents into the first 4KB of the storage. The data is synced
+    /// and read back to verify the write.
+    pub fn save_to_storage(&self, storage: &mut dyn MiscStorage) -> Result<(), std::io::Error> {
+        storage.write_verified(&self.to_bytes(), BOOTLOADER_MESSAGE_OFFSET_IN_MISC as u64)
+    }
+
+    /// Store the contents into the first 4KB of the storage and a backup copy
+    /// into the vendor space. The sequence number is increased first. The backup
+    /// is written only after the primary copy is verified, so one of the copies
+    /// is always valid.
+    pub fn save_to_storage_with_backup(
+        &mut self,
+        storage: &mut dyn MiscStorage,
+    ) -> Result<(), std::io::Error> {
+        self.set_sequence(self.sequence().wrapping_add(1));
+        self.save_to_storage(storage)?;
+        storage.write_verified(
+            &self.to_bytes(),
+            BACKUP_BOOTLOADER_MESSAGE_OFFSET_IN_MISC as u64,
+        )
     }
 }
 
-impl TryFrom<&[u8]> for &BootloaderMessageAB {
+impl TryFrom<&[u8]> for BootloaderMessageAB {
     type Error = BootloaderMessageError;
     fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
-        if data.len() < 4096 {
-            Err(BootloaderMessageError::InsufficientBytes)
-        } else {
-            let bytes = data.as_ptr();
-            let bolo_message_ab = bytes as *const BootloaderMessageAB;
-            let bolo_message_ab = unsafe { bolo_message_ab.as_ref().unwrap() };
-            Ok(bolo_message_ab)
+        Self::from_bytes(data)
+    }
+}
+
+/// Size of the serialized MiscVirtualABMessage
+pub const MISC_VIRTUAL_AB_MESSAGE_SIZE: usize = 64;
+/// The version of the virtual A/B message that is supported
+pub const MISC_VIRTUAL_AB_MESSAGE_VERSION: u8 = 2;
+/// Magic number of the virtual A/B message
+pub const MISC_VIRTUAL_AB_MAGIC_HEADER: u32 = 0x56740AB0;
+
+/// State of the snapshot merge of a virtual A/B update. The values are the
+/// ones of MergeStatus in the Android boot control HAL.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MergeStatus {
+    /// No snapshot or merge is in progress
+    None = 0,
+    /// The merge status could not be determined
+    Unknown = 1,
+    /// Partitions are snapshotted, the merge starts after booting the new slot
+    Snapshotted = 2,
+    /// The snapshots are being merged
+    Merging = 3,
+    /// The update was cancelled, the snapshots are discarded
+    Cancelled = 4,
+}
+
+impl TryFrom<u8> for MergeStatus {
+    type Error = BootloaderMessageError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(MergeStatus::None),
+            1 => Ok(MergeStatus::Unknown),
+            2 => Ok(MergeStatus::Snapshotted),
+            3 => Ok(MergeStatus::Merging),
+            4 => Ok(MergeStatus::Cancelled),
+            _ => Err(BootloaderMessageError::InvalidMergeStatus),
+        }
+    }
+}
+
+/// The virtual A/B message (64 bytes), as misc_virtual_ab_message in Android.
+/// It records the merge status of a snapshot based update and the slot that
+/// was running when the status was written.
+///
+/// Layout (integers are little endian):
+///
+/// 0   version       u8
+/// 1   magic         u32
+/// 5   merge_status  u8
+/// 6   source_slot   u8
+/// 7   reserved      [u8; 57]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MiscVirtualABMessage {
+    pub version: u8,
+    pub magic: u32,
+    merge_status: MergeStatus,
+    /// Slot number when merge_status was written
+    pub source_slot: u8,
+    reserved: [u8; 57],
+}
+
+impl Default for MiscVirtualABMessage {
+    fn default() -> Self {
+        MiscVirtualABMessage {
+            version: MISC_VIRTUAL_AB_MESSAGE_VERSION,
+            magic: MISC_VIRTUAL_AB_MAGIC_HEADER,
+            merge_status: MergeStatus::None,
+            source_slot: 0,
+            reserved: [0; 57],
+        }
+    }
+}
+
+impl MiscVirtualABMessage {
+    /// Parse the message. The magic number and the version are checked.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, BootloaderMessageError> {
+        if data.len() < MISC_VIRTUAL_AB_MESSAGE_SIZE {
+            return Err(BootloaderMessageError::InsufficientBytes);
+        }
+        let magic = u32::from_le_bytes(read_array(data, 1));
+        if magic != MISC_VIRTUAL_AB_MAGIC_HEADER {
+            return Err(BootloaderMessageError::InvalidMagic);
+        }
+        if data[0] != MISC_VIRTUAL_AB_MESSAGE_VERSION {
+            return Err(BootloaderMessageError::UnsupportedVersion);
         }
+        Ok(MiscVirtualABMessage {
+            version: data[0],
+            magic,
+            merge_status: MergeStatus::try_from(data[5])?,
+            source_slot: data[6],
+            reserved: read_array(data, 7),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; MISC_VIRTUAL_AB_MESSAGE_SIZE] {
+        let mut data = [0u8; MISC_VIRTUAL_AB_MESSAGE_SIZE];
+        data[0] = self.version;
+        data[1..5].copy_from_slice(&self.magic.to_le_bytes());
+        data[5] = self.merge_status as u8;
+        data[6] = self.source_slot;
+        data[7..64].copy_from_slice(&self.reserved);
+        data
+    }
+
+    /// Read the message from the vendor space of the misc storage
+    pub fn load(storage: &dyn MiscStorage) -> Result<Self, std::io::Error> {
+        let mut data = [0u8; MISC_VIRTUAL_AB_MESSAGE_SIZE];
+        storage.read_at(&mut data, VIRTUAL_AB_MESSAGE_OFFSET_IN_MISC as u64)?;
+        Self::from_bytes(&data).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    /// Write the message into the vendor space of the misc storage
+    pub fn save(&self, storage: &mut dyn MiscStorage) -> Result<(), std::io::Error> {
+        storage.write_verified(&self.to_bytes(), VIRTUAL_AB_MESSAGE_OFFSET_IN_MISC as u64)
+    }
+
+    pub fn merge_status(&self) -> MergeStatus {
+        self.merge_status
+    }
+
+    /// Set the merge status and the slot that is running
+    pub fn set_merge_status(&mut self, merge_status: MergeStatus, source_slot: u8) {
+        self.merge_status = merge_status;
+        self.source_slot = source_slot;
     }
 }
 
-#[derive(Debug, Clone, Copy, BitfieldStruct)]
+/// Magic number of the bootloader control ("BCAB" in little endian)
+pub const BOOT_CTRL_MAGIC: u32 = 0x42414342;
+/// The version of the bootloader control that is supported
+pub const BOOT_CTRL_VERSION: u8 = 1;
+/// The default priority of a slot after initialization
+pub const DEFAULT_SLOT_PRIORITY: u8 = 15;
+/// The default number of tries of a slot after initialization
+pub const DEFAULT_SLOT_TRIES: u8 = 7;
+
+#[derive(Debug, Clone, Copy, Default, BitfieldStruct)]
 #[repr(C, packed)]
 pub struct BootloaderControl {
     // NUL terminated active slot suffix.
     pub slot_suffix: [u8; 4],
-    // Bootloader Control AB magic number (see BOOT_CTRL_MAGIC).
+    // Bootloader Control AB magic number (see BOOT_CTRL_MAGIC). Stored
+    // in little endian format, held in native format here.
     pub magic: u32,
     version: u8,
     // Number of slots being managed.
@@ -269,11 +696,108 @@ pub struct BootloaderControl {
     // Reserved for further use.
     reserved1: [u8; 8],
     // CRC32 of all 28 bytes preceding this field (little endian
-    // format).
+    // format). Computed when the structure is serialized.
     crc32_le: u32,
 }
 
+/*
+  Serialized layout of the bootloader control (integers are little endian):
+
+  0   slot_suffix         [u8; 4]
+  4   magic               u32
+  8   version             u8
+  9   nb_slot, recovery_tries_remaining
+  10  reserved0           [u8; 2]
+  12  slot_info           [[u8; 2]; 4]
+  20  reserved1           [u8; 8]
+  28  crc32_le            u32
+*/
+
 impl BootloaderControl {
+    /// Create the default bootloader control, the same way the bootloader does it.
+    /// All slots get the default priority and tries and slot "a" is active.
+    /// The CRC is set when the containing BootloaderMessageAB is written out.
+    pub fn new(nb_slot: u8) -> Result<Self, BootloaderMessageError> {
+        let mut control = BootloaderControl {
+            magic: BOOT_CTRL_MAGIC,
+            version: BOOT_CTRL_VERSION,
+            ..Default::default()
+        };
+
+        if nb_slot == 0 || nb_slot as usize > control.slot_info.len() {
+            return Err(BootloaderMessageError::InvalidSlotCount);
+        }
+
+        control.set_nb_slot(nb_slot);
+        control.set_slot_suffix("a")?;
+        for slot in control.slot_info[0..nb_slot as usize].iter_mut() {
+            slot.set_priority(DEFAULT_SLOT_PRIORITY);
+            slot.set_tries_remaining(DEFAULT_SLOT_TRIES);
+        }
+        Ok(control)
+    }
+
+    /// Check the magic number and the version
+    pub fn validate(&self) -> Result<(), BootloaderMessageError> {
+        if self.magic != BOOT_CTRL_MAGIC {
+            Err(BootloaderMessageError::InvalidMagic)
+        } else if self.version > BOOT_CTRL_VERSION {
+            Err(BootloaderMessageError::UnsupportedVersion)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Parse the bootloader control from the first BOOTLOADER_CONTROL_SIZE bytes
+    /// of the data. Neither the CRC nor the magic number are checked here.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, BootloaderMessageError> {
+        if data.len() < BOOTLOADER_CONTROL_SIZE {
+            return Err(BootloaderMessageError::InsufficientBytes);
+        }
+        let mut slot_info = [SlotMetadata::default(); 4];
+        for (index, slot) in slot_info.iter_mut().enumerate() {
+            let offset = 12 + 2 * index;
+            slot.data0 = [data[offset]];
+            slot.data1 = [data[offset + 1]];
+        }
+        Ok(BootloaderControl {
+            slot_suffix: read_array(data, 0),
+            magic: u32::from_le_bytes(read_array(data, 4)),
+            version: data[8],
+            bitfield1: [data[9]],
+            reserved0: read_array(data, 10),
+            slot_info,
+            reserved1: read_array(data, 20),
+            crc32_le: u32::from_le_bytes(read_array(data, 28)),
+        })
+    }
+
+    /// Serialize the bootloader control. The CRC is computed over the first 28 bytes.
+    pub fn to_bytes(&self) -> [u8; BOOTLOADER_CONTROL_SIZE] {
+        let mut data = [0u8; BOOTLOADER_CONTROL_SIZE];
+        data[0..4].copy_from_slice(&self.slot_suffix);
+        data[4..8].copy_from_slice(&{ self.magic }.to_le_bytes());
+        data[8] = self.version;
+        data[9] = self.bitfield1[0];
+        data[10..12].copy_from_slice(&self.reserved0);
+        for (index, slot) in self.slot_info.iter().enumerate() {
+            let offset = 12 + 2 * index;
+            data[offset] = slot.data0[0];
+            data[offset + 1] = slot.data1[0];
+        }
+        data[20..28].copy_from_slice(&self.reserved1);
+
+        let algo = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+        let crc32 = algo.checksum(&data[0..28]);
+        data[28..32].copy_from_slice(&crc32.to_le_bytes());
+        data
+    }
+
+    /// Number of slots being managed, limited to the slots that fit in slot_info.
+    pub fn slot_count(&self) -> usize {
+        std::cmp::min(self.nb_slot() as usize, self.slot_info.len())
+    }
+
     pub fn slot_suffix(&self) -> Result<&CStr, BootloaderMessageError> {
         let slot_suffix_bytes = self.slot_suffix.as_slice();
         if let Some(null_position) = slot_suffix_bytes.iter().position(|d| *d == 0) {
@@ -299,9 +823,86 @@ impl BootloaderControl {
             Ok(())
         }
     }
+
+    /// Make the slot active, the way the Android boot control HAL does it. The
+    /// slot gets the highest priority and a full count of tries and the other
+    /// slots are lowered below it.
+    ///
+    /// A slot that is not running is a freshly installed one, so its successful
+    /// and verity corrupted flags are cleared. Making the running slot active
+    /// again cancels a pending update and keeps its flags.
+    pub fn set_active_slot(
+        &mut self,
+        slot_index: usize,
+        is_current_slot: bool,
+    ) -> Result<(), BootloaderMessageError> {
+        if slot_index >= self.slot_count() {
+            return Err(BootloaderMessageError::InvalidSlotIndex);
+        }
+
+        let slot_count = self.slot_count();
+        for (index, slot) in self.slot_info[0..slot_count].iter_mut().enumerate() {
+            if index != slot_index && slot.priority() >= MAX_SLOT_PRIORITY {
+                slot.set_priority(MAX_SLOT_PRIORITY - 1);
+            }
+        }
+
+        let slot = &mut self.slot_info[slot_index];
+        slot.set_priority(MAX_SLOT_PRIORITY);
+        slot.set_tries_remaining(MAX_TRIES);
+        if !is_current_slot {
+            slot.set_successful_boot(0);
+            slot.set_verity_corrupted(0);
+        }
+
+        let suffix = slot_suffix_from_index(slot_index)
+            .map_err(|_| BootloaderMessageError::InvalidSlotIndex)?;
+        self.set_slot_suffix(suffix)
+    }
+
+    /// Record that dm-verity found the slot corrupted. If another slot is bootable,
+    /// the corrupted slot is also made unbootable so that the bootloader falls back
+    /// to the other slot. The last bootable slot is only marked.
+    pub fn set_slot_verity_corrupted(
+        &mut self,
+        slot_index: usize,
+    ) -> Result<(), BootloaderMessageError> {
+        if slot_index >= self.slot_count() {
+            return Err(BootloaderMessageError::InvalidSlotIndex);
+        }
+
+        let other_slot_bootable = self.slot_info[0..self.slot_count()]
+            .iter()
+            .enumerate()
+            .any(|(index, slot)| index != slot_index && slot.is_bootable());
+
+        let slot = &mut self.slot_info[slot_index];
+        slot.set_verity_corrupted(1);
+        if other_slot_bootable {
+            slot.set_successful_boot(0);
+            slot.set_tries_remaining(0);
+        } else {
+            log::error!("No other bootable slot, keeping the tries of the corrupted slot");
+        }
+        Ok(())
+    }
+
+    /// Make the slot unbootable, the way the Android boot control HAL does it.
+    /// The bootloader skips a slot without priority, tries and successful boot.
+    pub fn set_slot_unbootable(&mut self, slot_index: usize) -> Result<(), BootloaderMessageError> {
+        if slot_index >= self.slot_count() {
+            return Err(BootloaderMessageError::InvalidSlotIndex);
+        }
+
+        let slot = &mut self.slot_info[slot_index];
+        slot.set_priority(0);
+        slot.set_tries_remaining(0);
+        slot.set_successful_boot(0);
+        Ok(())
+    }
 }
 
-#[derive(Debug, Clone, Copy, BitfieldStruct)]
+#[derive(Debug, Clone, Copy, Default, BitfieldStruct)]
 #[repr(C, packed)]
 pub struct SlotMetadata {
     // Slot priority with 15 meaning highest priority, 1 lowest
@@ -330,12 +931,247 @@ impl Display for SlotMetadata {
     }
 }
 
+/// Maximum priority of a slot
+pub const MAX_SLOT_PRIORITY: u8 = 15;
+/// Maximum number of tries of a slot, and of recovery
+pub const MAX_TRIES: u8 = 7;
+
+// Human readable representations of the structures, used for
+// serialization with serde (JSON export and test fixtures).
+
+#[derive(Serialize, Deserialize)]
+struct SlotMetadataRepr {
+    priority: u8,
+    tries_remaining: u8,
+    successful_boot: bool,
+    verity_corrupted: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BootloaderControlRepr {
+    slot_suffix: String,
+    version: u8,
+    recovery_tries_remaining: u8,
+    slots: Vec<SlotMetadata>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    reserved0: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    reserved1: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BootloaderMessageRepr {
+    command: String,
+    status: String,
+    recovery: String,
+    stage: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    reserved: Vec<u8>,
+}
+
+/// A bootloader control that is not valid, kept as raw bytes so that
+/// the export shows what is stored and the import restores it unchanged
+#[derive(Serialize, Deserialize)]
+struct InvalidBootloaderControlRepr {
+    error: String,
+    bytes: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BootloaderMessageABRepr {
+    message: BootloaderMessage,
+    /// None if the bootloader control is not valid
+    bootloader_control: Option<BootloaderControl>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    invalid_bootloader_control: Option<InvalidBootloaderControlRepr>,
+    update_channel: String,
+    /// Holds the sequence number of the copy
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    reserved: Vec<u8>,
+}
+
+impl Serialize for SlotMetadata {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SlotMetadataRepr {
+            priority: self.priority(),
+            tries_remaining: self.tries_remaining(),
+            successful_boot: self.successful_boot() == 1,
+            verity_corrupted: self.verity_corrupted() == 1,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for SlotMetadata {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = SlotMetadataRepr::deserialize(deserializer)?;
+        if repr.priority > MAX_SLOT_PRIORITY {
+            return Err(D::Error::custom(BootloaderMessageError::PriorityOutOfRange));
+        }
+        if repr.tries_remaining > MAX_TRIES {
+            return Err(D::Error::custom(BootloaderMessageError::TriesOutOfRange));
+        }
+
+        let mut slot = SlotMetadata::default();
+        slot.set_priority(repr.priority);
+        slot.set_tries_remaining(repr.tries_remaining);
+        slot.set_successful_boot(repr.successful_boot as u8);
+        slot.set_verity_corrupted(repr.verity_corrupted as u8);
+        Ok(slot)
+    }
+}
+
+impl Serialize for BootloaderControl {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let slot_suffix = self.slot_suffix().map_err(S::Error::custom)?;
+        BootloaderControlRepr {
+            slot_suffix: slot_suffix.to_str().map_err(S::Error::custom)?.to_owned(),
+            version: self.version,
+            recovery_tries_remaining: self.recovery_tries_remaining(),
+            slots: self.slot_info[0..self.slot_count()].to_vec(),
+            reserved0: reserved_to_vec(&self.reserved0),
+            reserved1: reserved_to_vec(&self.reserved1),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for BootloaderControl {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = BootloaderControlRepr::deserialize(deserializer)?;
+        if repr.recovery_tries_remaining > MAX_TRIES {
+            return Err(D::Error::custom(BootloaderMessageError::TriesOutOfRange));
+        }
+        let nb_slot = u8::try_from(repr.slots.len())
+            .map_err(|_e| D::Error::custom(BootloaderMessageError::InvalidSlotCount))?;
+
+        let mut control = BootloaderControl::new(nb_slot).map_err(D::Error::custom)?;
+        control
+            .set_slot_suffix(&repr.slot_suffix)
+            .map_err(D::Error::custom)?;
+        control.version = repr.version;
+        control.validate().map_err(D::Error::custom)?;
+        control.set_recovery_tries_remaining(repr.recovery_tries_remaining);
+        control.slot_info[0..repr.slots.len()].copy_from_slice(&repr.slots);
+        vec_to_reserved(&mut control.reserved0, &repr.reserved0).map_err(D::Error::custom)?;
+        vec_to_reserved(&mut control.reserved1, &repr.reserved1).map_err(D::Error::custom)?;
+        Ok(control)
+    }
+}
+
+impl Serialize for BootloaderMessage {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        BootloaderMessageRepr {
+            command: field_to_str(&self.command)
+                .map_err(S::Error::custom)?
+                .to_owned(),
+            status: self.status().map_err(S::Error::custom)?.to_owned(),
+            recovery: field_to_str(&self.recovery)
+                .map_err(S::Error::custom)?
+                .to_owned(),
+            stage: self.stage().map_err(S::Error::custom)?.to_owned(),
+            reserved: reserved_to_vec(&self.reserved),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for BootloaderMessage {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = BootloaderMessageRepr::deserialize(deserializer)?;
+        let mut message = BootloaderMessage {
+            command: [0; 32],
+            status: [0; 32],
+            recovery: [0; 768],
+            stage: [0; 32],
+            reserved: [0; 1184],
+        };
+        str_to_field(&mut message.command, &repr.command).map_err(D::Error::custom)?;
+        str_to_field(&mut message.status, &repr.status).map_err(D::Error::custom)?;
+        str_to_field(&mut message.recovery, &repr.recovery).map_err(D::Error::custom)?;
+        str_to_field(&mut message.stage, &repr.stage).map_err(D::Error::custom)?;
+        vec_to_reserved(&mut message.reserved, &repr.reserved).map_err(D::Error::custom)?;
+        Ok(message)
+    }
+}
+
+impl Serialize for BootloaderMessageAB {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (bootloader_control, invalid_bootloader_control) = match self.get_bootloader_control() {
+            Ok(control) => (Some(control), None),
+            Err(e) => (
+                None,
+                Some(InvalidBootloaderControlRepr {
+                    error: e.to_string(),
+                    bytes: self.slot_suffix.to_vec(),
+                }),
+            ),
+        };
+        BootloaderMessageABRepr {
+            message: self.message,
+            bootloader_control,
+            invalid_bootloader_control,
+            update_channel: self.update_channel().map_err(S::Error::custom)?.to_owned(),
+            reserved: reserved_to_vec(&self.reserved),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for BootloaderMessageAB {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = BootloaderMessageABRepr::deserialize(deserializer)?;
+        let mut message_ab = BootloaderMessageAB {
+            message: repr.message,
+            slot_suffix: [0; 32],
+            update_channel: [0; 128],
+            reserved: [0; 1888],
+        };
+        match (repr.bootloader_control, repr.invalid_bootloader_control) {
+            (Some(control), None) => message_ab.set_bootloader_control(&control),
+            (None, Some(invalid)) => {
+                if invalid.bytes.len() != message_ab.slot_suffix.len() {
+                    return Err(D::Error::invalid_length(
+                        invalid.bytes.len(),
+                        &"the size of the bootloader control",
+                    ));
+                }
+                message_ab.slot_suffix.copy_from_slice(&invalid.bytes);
+            }
+            (None, None) => {}
+            (Some(_), Some(_)) => {
+                return Err(D::Error::custom(
+                    "both a valid and an invalid bootloader control",
+                ))
+            }
+        }
+        message_ab
+            .set_update_channel(&repr.update_channel)
+            .map_err(D::Error::custom)?;
+        vec_to_reserved(&mut message_ab.reserved, &repr.reserved).map_err(D::Error::custom)?;
+        Ok(message_ab)
+    }
+}
+
+impl BootloaderMessageAB {
+    /// Export the message as JSON
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string_pretty(self)
+    }
+
+    /// Import the message from JSON
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+}
+
 #[cfg(test)]
 mod test {
 
     use std::convert::TryInto;
 
     use super::*;
+    use crate::bootloader::storage::{misc_from_testdata, MemoryStorage};
     #[test]
     fn check_sizes() {
         assert_eq!(std::mem::size_of::<BootloaderMessage>(), 2048);
@@ -344,10 +1180,45 @@ mod test {
         assert_eq!(std::mem::size_of::<BootloaderControl>(), 32);
     }
 
+    #[test]
+    fn reject_short_buffers() {
+        let bytes_slice = include_bytes!("./testdata/bolomessage.dat");
+        for len in [0, 31, 2047, 4095].iter() {
+            assert!(matches!(
+                BootloaderMessageAB::from_bytes(&bytes_slice[0..*len]),
+                Err(BootloaderMessageError::InsufficientBytes)
+            ));
+        }
+        assert!(matches!(
+            BootloaderMessage::from_bytes(&bytes_slice[0..2047]),
+            Err(BootloaderMessageError::InsufficientBytes)
+        ));
+        assert!(matches!(
+            BootloaderControl::from_bytes(&bytes_slice[2048..2079]),
+            Err(BootloaderMessageError::InsufficientBytes)
+        ));
+        assert!(BootloaderMessageAB::from_bytes(&bytes_slice[..]).is_ok());
+    }
+
+    #[test]
+    fn control_byte_order() {
+        let control = BootloaderControl::new(2).unwrap();
+        let bytes = control.to_bytes();
+        assert_eq!(&bytes[4..8], &[0x42, 0x43, 0x41, 0x42]);
+
+        let algo = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+        let crc32 = algo.checksum(&bytes[0..28]);
+        assert_eq!(&bytes[28..32], &crc32.to_le_bytes());
+
+        let parsed = BootloaderControl::from_bytes(&bytes).unwrap();
+        assert_eq!({ parsed.magic }, BOOT_CTRL_MAGIC);
+        assert_eq!(parsed.to_bytes(), bytes);
+    }
+
     #[test]
     fn read_bolo_message() {
         let bytes_slice = include_bytes!("./testdata/bolomessage.dat");
-        let bolo_message_ab: &BootloaderMessageAB = bytes_slice.as_slice().try_into().unwrap();
+        let bolo_message_ab: BootloaderMessageAB = bytes_slice.as_slice().try_into().unwrap();
 
         let ctrl = bolo_message_ab.get_bootloader_control().unwrap();
 
@@ -379,10 +1250,9 @@ mod test {
         }
 
         let mut copy = bolo_message_ab.clone();
-        let original_as_slice = copy.as_slice();
-        assert_eq!(original_as_slice, bytes_slice);
+        assert_eq!(copy.to_bytes(), bytes_slice);
 
-        let control = copy.get_bootloader_control_mut().unwrap();
+        let mut control = copy.get_bootloader_control().unwrap();
         control.slot_info[0].set_successful_boot(1);
         for s in control.slot_info.as_ref().iter() {
             println!("SlotMetadata:{:?}", s.to_string());
@@ -405,7 +1275,335 @@ mod test {
         println!("Current slot is {:?}", current_suffix);
         //}
 
-        let slice = copy.as_slice();
+        copy.set_bootloader_control(&control);
+        let slice = copy.to_bytes();
         assert_eq!(slice.len(), 4096);
+        assert_eq!(
+            copy.get_bootloader_control()
+                .unwrap()
+                .slot_suffix()
+                .unwrap()
+                .to_str()
+                .unwrap(),
+            "b"
+        );
+    }
+
+    #[test]
+    fn bootloader_command() {
+        let bytes_slice = include_bytes!("./testdata/bolomessage.dat");
+        let bolo_message_ab: BootloaderMessageAB = bytes_slice.as_slice().try_into().unwrap();
+        let mut message = bolo_message_ab.message;
+
+        message.clear();
+        assert_eq!(message.command().unwrap(), None);
+        assert!(message.recovery_args().unwrap().is_empty());
+
+        message
+            .set_boot_recovery(&["--wipe_data", "--locale=en_US"])
+            .unwrap();
+        assert_eq!(
+            message.command().unwrap(),
+            Some(BootloaderCommand::BootRecovery)
+        );
+        assert_eq!(
+            message.recovery_args().unwrap(),
+            vec!["--wipe_data", "--locale=en_US"]
+        );
+        assert_eq!(&message.recovery[0..21], b"recovery\n--wipe_data\n");
+
+        message.set_stage("1/3").unwrap();
+        assert_eq!(message.stage().unwrap(), "1/3");
+        assert!(matches!(
+            message.set_stage("a stage string that is far too long"),
+            Err(BootloaderMessageError::DataTooLong)
+        ));
+
+        message
+            .set_command(&BootloaderCommand::BootonceBootloader)
+            .unwrap();
+        assert_eq!(
+            message.command().unwrap(),
+            Some(BootloaderCommand::BootonceBootloader)
+        );
+        message.clear_command();
+        assert_eq!(message.command().unwrap(), None);
+    }
+
+    #[test]
+    fn save_and_restore_from_storage() {
+        let mut storage = misc_from_testdata();
+
+        let mut message = BootloaderMessageAB::create_from_storage(&storage).unwrap();
+        assert_eq!(message.to_bytes(), &storage.as_slice()[0..4096]);
+
+        message.message.set_stage("2/3").unwrap();
+        message.save_to_storage(&mut storage).unwrap();
+
+        let restored = BootloaderMessageAB::create_from_storage(&storage).unwrap();
+        assert_eq!(restored.message.stage().unwrap(), "2/3");
+        assert_eq!(&storage.as_slice()[4096..], &[0u8; 60 * 1024][..]);
+    }
+
+    #[test]
+    fn update_channel() {
+        let mut storage = misc_from_testdata();
+
+        let mut message = BootloaderMessageAB::create_from_storage(&storage).unwrap();
+        assert_eq!(message.update_channel().unwrap(), "");
+        message.set_update_channel("beta-channel").unwrap();
+        message.save_to_storage(&mut storage).unwrap();
+
+        let mut message = BootloaderMessageAB::create_from_storage(&storage).unwrap();
+        assert_eq!(message.update_channel().unwrap(), "beta-channel");
+        // a shorter channel does not leave parts of the previous one behind
+        message.set_update_channel("stable").unwrap();
+        assert_eq!(message.update_channel().unwrap(), "stable");
+        assert_eq!(&message.update_channel[6..12], &[0u8; 6]);
+
+        assert!(matches!(
+            message.set_update_channel(&"x".repeat(129)),
+            Err(BootloaderMessageError::DataTooLong)
+        ));
+        assert!(matches!(
+            message.set_update_channel("sta\0ble"),
+            Err(BootloaderMessageError::InvalidString)
+        ));
+        // the full field is used without a NUL byte
+        message.set_update_channel(&"x".repeat(128)).unwrap();
+        assert_eq!(message.update_channel().unwrap(), "x".repeat(128));
+        message.clear_update_channel();
+        assert_eq!(message.update_channel().unwrap(), "");
+
+        // invalid UTF-8 left behind by other software is reported
+        message.update_channel[0] = 0xff;
+        assert!(matches!(
+            message.update_channel(),
+            Err(BootloaderMessageError::InvalidString)
+        ));
+    }
+
+    #[test]
+    fn initialize_bootloader_control() {
+        let bytes_slice = include_bytes!("./testdata/bolomessage.dat");
+        let bolo_message_ab: BootloaderMessageAB = bytes_slice.as_slice().try_into().unwrap();
+        let mut copy = bolo_message_ab.clone();
+
+        // a wiped misc partition
+        copy.slot_suffix = [0u8; 32];
+        assert!(matches!(
+            copy.get_bootloader_control(),
+            Err(BootloaderMessageError::CrcFailure)
+        ));
+        let algo = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+        let crc32 = algo.checksum(&copy.slot_suffix[0..28]);
+        copy.slot_suffix[28..32].copy_from_slice(&crc32.to_le_bytes());
+        assert!(matches!(
+            copy.get_bootloader_control(),
+            Err(BootloaderMessageError::InvalidMagic)
+        ));
+
+        assert!(matches!(
+            copy.initialize_bootloader_control(5),
+            Err(BootloaderMessageError::InvalidSlotCount)
+        ));
+        copy.initialize_bootloader_control(3).unwrap();
+
+        let ctrl = copy.get_bootloader_control().unwrap();
+        assert_eq!(ctrl.nb_slot(), 3);
+        assert_eq!(ctrl.slot_suffix().unwrap().to_str().unwrap(), "a");
+        for slot in ctrl.slot_info[0..3].iter() {
+            assert_eq!(slot.priority(), DEFAULT_SLOT_PRIORITY);
+            assert_eq!(slot.tries_remaining(), DEFAULT_SLOT_TRIES);
+            assert_eq!(slot.successful_boot(), 0);
+        }
+        assert_eq!(ctrl.slot_info[3].priority(), 0);
+        assert_eq!(&copy.slot_suffix[4..8], b"BCAB");
+    }
+
+    #[test]
+    fn fallback_to_backup_copy() {
+        let mut storage = misc_from_testdata();
+
+        let mut message = BootloaderMessageAB::create_from_storage(&storage).unwrap();
+        assert_eq!(message.sequence(), 0);
+        let mut control = message.get_bootloader_control().unwrap();
+        control.set_slot_suffix("b").unwrap();
+        message.set_bootloader_control(&control);
+        message.save_to_storage_with_backup(&mut storage).unwrap();
+        assert_eq!(message.sequence(), 1);
+
+        // corrupt the primary copy of the bootloader control
+        storage.write_at(&[0xff; 4], 2048 + 8).unwrap();
+
+        let message = BootloaderMessageAB::create_from_storage_with_backup(&storage).unwrap();
+        let control = message.get_bootloader_control().unwrap();
+        assert_eq!(control.slot_suffix().unwrap().to_str().unwrap(), "b");
+        assert_eq!(message.sequence(), 1);
+
+        // the backup is only used when asked for
+        let message = BootloaderMessageAB::create_from_storage(&storage).unwrap();
+        assert!(message.get_bootloader_control().is_err());
+
+        // without a backup, the error is reported
+        let mut storage = misc_from_testdata();
+        storage.write_at(&[0xff; 4], 2048 + 8).unwrap();
+        let message = BootloaderMessageAB::create_from_storage_with_backup(&storage).unwrap();
+        assert!(message.get_bootloader_control().is_err());
+    }
+
+    #[test]
+    fn newest_copy_wins() {
+        let mut storage = misc_from_testdata();
+        let mut message = BootloaderMessageAB::create_from_storage(&storage).unwrap();
+        message.save_to_storage_with_backup(&mut storage).unwrap();
+        let old_copy = message.to_bytes();
+
+        let mut control = message.get_bootloader_control().unwrap();
+        control.set_slot_suffix("b").unwrap();
+        message.set_bootloader_control(&control);
+        message.save_to_storage_with_backup(&mut storage).unwrap();
+
+        // the bootloader uses up a try in the primary copy, the sequence is unchanged
+        let mut primary = BootloaderMessageAB::create_from_storage(&storage).unwrap();
+        let mut control = primary.get_bootloader_control().unwrap();
+        control.slot_info[1].set_tries_remaining(2);
+        primary.set_bootloader_control(&control);
+        primary.save_to_storage(&mut storage).unwrap();
+        let message = BootloaderMessageAB::create_from_storage_with_backup(&storage).unwrap();
+        let control = message.get_bootloader_control().unwrap();
+        assert_eq!(control.slot_info[1].tries_remaining(), 2);
+
+        // an older primary copy, the backup is newer
+        storage.write_at(&old_copy, 0).unwrap();
+        let message = BootloaderMessageAB::create_from_storage_with_backup(&storage).unwrap();
+        let control = message.get_bootloader_control().unwrap();
+        assert_eq!(control.slot_suffix().unwrap().to_str().unwrap(), "b");
+        assert_eq!(message.sequence(), 2);
+    }
+
+    #[test]
+    fn virtual_ab_message() {
+        let mut storage = MemoryStorage::new(64 * 1024);
+        assert!(MiscVirtualABMessage::load(&storage).is_err());
+
+        let mut message = MiscVirtualABMessage::default();
+        message.set_merge_status(MergeStatus::Snapshotted, 1);
+        message.save(&mut storage).unwrap();
+        assert_eq!(
+            &storage.as_slice()[12 * 1024..12 * 1024 + 7],
+            &[2, 0xb0, 0x0a, 0x74, 0x56, 2, 1]
+        );
+
+        let loaded = MiscVirtualABMessage::load(&storage).unwrap();
+        assert_eq!(loaded, message);
+        assert_eq!(loaded.merge_status(), MergeStatus::Snapshotted);
+        assert_eq!(loaded.source_slot, 1);
+
+        let mut data = message.to_bytes();
+        data[5] = 5;
+        assert!(matches!(
+            MiscVirtualABMessage::from_bytes(&data),
+            Err(BootloaderMessageError::InvalidMergeStatus)
+        ));
+        assert!(matches!(
+            MiscVirtualABMessage::from_bytes(&data[0..63]),
+            Err(BootloaderMessageError::InsufficientBytes)
+        ));
+    }
+
+    #[test]
+    fn json_fixture_matches_binary() {
+        let bytes_slice = include_bytes!("./testdata/bolomessage.dat");
+        let json = include_str!("./testdata/bolomessage.json");
+
+        let from_json = BootloaderMessageAB::from_json(json).unwrap();
+        assert_eq!(from_json.to_bytes(), bytes_slice);
+
+        let bolo_message_ab: BootloaderMessageAB = bytes_slice.as_slice().try_into().unwrap();
+        let exported = bolo_message_ab.to_json().unwrap();
+        let reimported = BootloaderMessageAB::from_json(&exported).unwrap();
+        assert_eq!(reimported.to_bytes(), bytes_slice);
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let json = include_str!("./testdata/bolomessage.json");
+        let mut message = BootloaderMessageAB::from_json(json).unwrap();
+        message.message.set_boot_recovery(&["--wipe_data"]).unwrap();
+        let mut control = message.get_bootloader_control().unwrap();
+        control.slot_info[1].set_successful_boot(1);
+        control.slot_info[0].set_verity_corrupted(1);
+        control.set_recovery_tries_remaining(3);
+        message.set_bootloader_control(&control);
+
+        let value: serde_json::Value = serde_json::from_str(&message.to_json().unwrap()).unwrap();
+        assert_eq!(value["message"]["command"], "boot-recovery");
+        assert_eq!(value["message"]["recovery"], "recovery\n--wipe_data\n");
+        assert_eq!(value["bootloader_control"]["recovery_tries_remaining"], 3);
+        assert_eq!(
+            value["bootloader_control"]["slots"][1]["successful_boot"],
+            true
+        );
+        assert_eq!(
+            value["bootloader_control"]["slots"][0]["verity_corrupted"],
+            true
+        );
+
+        let restored = BootloaderMessageAB::from_json(&message.to_json().unwrap()).unwrap();
+        assert_eq!(restored.to_bytes(), message.to_bytes());
+    }
+
+    #[test]
+    fn json_export_of_corrupted_control() {
+        let json = include_str!("./testdata/bolomessage.json");
+        let mut message = BootloaderMessageAB::from_json(json).unwrap();
+        message.slot_suffix[28] ^= 0xff;
+
+        let exported = message.to_json().unwrap();
+        let value: serde_json::Value = serde_json::from_str(&exported).unwrap();
+        assert!(value["bootloader_control"].is_null());
+        assert_eq!(value["invalid_bootloader_control"]["error"], "CRC Error");
+        assert_eq!(
+            value["invalid_bootloader_control"]["bytes"]
+                .as_array()
+                .unwrap()
+                .len(),
+            32
+        );
+
+        let reimported = BootloaderMessageAB::from_json(&exported).unwrap();
+        assert_eq!(reimported.to_bytes(), message.to_bytes());
+        assert!(reimported.get_bootloader_control().is_err());
+    }
+
+    #[test]
+    fn json_out_of_range() {
+        let json = include_str!("./testdata/bolomessage.json")
+            .replace("\"priority\": 15", "\"priority\": 16");
+        assert!(BootloaderMessageAB::from_json(&json).is_err());
+
+        let json =
+            include_str!("./testdata/bolomessage.json").replace("\"version\": 1", "\"version\": 2");
+        assert!(BootloaderMessageAB::from_json(&json).is_err());
+    }
+
+    #[test]
+    fn json_keeps_full_fields_and_reserved_bytes() {
+        let json = include_str!("./testdata/bolomessage.json");
+        let mut message = BootloaderMessageAB::from_json(json).unwrap();
+        message.message.set_stage(&"1".repeat(32)).unwrap();
+        message.set_update_channel(&"c".repeat(128)).unwrap();
+        message.set_sequence(7);
+        message.message.reserved[1183] = 0x5a;
+        let mut control = message.get_bootloader_control().unwrap();
+        control.reserved1[7] = 0xa5;
+        message.set_bootloader_control(&control);
+
+        let restored = BootloaderMessageAB::from_json(&message.to_json().unwrap()).unwrap();
+        assert_eq!(restored.message.stage().unwrap(), "1".repeat(32));
+        assert_eq!(restored.update_channel().unwrap(), "c".repeat(128));
+        assert_eq!(restored.sequence(), 7);
+        assert_eq!(restored.to_bytes(), message.to_bytes());
     }
 }
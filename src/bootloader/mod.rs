@@ -1,3 +1,12 @@
+pub mod bootcontrol;
 pub mod error;
-mod message;
-pub mod bootcontrol;
\ No newline at end of file
+pub mod grub_bootcontrol;
+pub mod grubenv;
+pub mod kvstore;
+pub mod message;
+pub mod rollback_index;
+mod slot_select;
+pub mod storage;
+pub mod uboot_bootcontrol;
+pub mod uboot_env;
+pub mod wipe_package;
@@ -0,0 +1,320 @@
+/*
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+/*
+  Anti-rollback indexes in the vendor space of the misc partition.
+
+  Every rollback index location holds a monotonic counter. An image whose
+  rollback index is lower than the stored counter must not be used. When a
+  slot boots, the rollback index of its image is staged as pending for that
+  slot. The counters are only raised to the pending values once the slot is
+  marked as successfully booted, so that a new image that fails to boot does
+  not lock out the previous one.
+
+  Only a blank store (all 0x00 or all 0xff) reads as zero indexes. A store
+  with a bad magic or CRC is an error, it must not be replaced by zeros as
+  that would allow a rollback.
+
+  Layout (all integers are little endian):
+
+  0   magic        "SRBI"
+  4   version      u8
+  5   reserved     [u8; 3]
+  8   payload_len  u32
+  12  crc32        u32, CRC32 of the payload
+  16  payload
+        rollback_index  [u64; ROLLBACK_INDEX_LOCATIONS]
+        pending         [[u64; ROLLBACK_INDEX_LOCATIONS]; ROLLBACK_INDEX_SLOTS]
+*/
+
+use crc::{Crc, CRC_32_ISO_HDLC};
+
+use super::error::BootloaderMessageError;
+use super::storage::MiscStorage;
+
+/// The indexes are placed after the misc_virtual_ab_message
+pub const ROLLBACK_INDEX_OFFSET_IN_MISC: usize = 12 * 1024 + 512;
+/// Space reserved for the indexes, including the header
+pub const ROLLBACK_INDEX_STORE_SIZE: usize = 256;
+/// Number of rollback index locations
+pub const ROLLBACK_INDEX_LOCATIONS: usize = 4;
+/// The location used for the rollback index of the verity header
+pub const VERITY_ROLLBACK_INDEX_LOCATION: usize = 0;
+/// Number of slots that pending indexes are kept for
+const ROLLBACK_INDEX_SLOTS: usize = 4;
+
+const ROLLBACK_INDEX_MAGIC: [u8; 4] = *b"SRBI";
+const ROLLBACK_INDEX_VERSION: u8 = 1;
+const HEADER_SIZE: usize = 16;
+const PAYLOAD_SIZE: usize = 8 * ROLLBACK_INDEX_LOCATIONS * (1 + ROLLBACK_INDEX_SLOTS);
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RollbackIndexes {
+    rollback_index: [u64; ROLLBACK_INDEX_LOCATIONS],
+    /// Indexes of the images that booted in each slot, not yet committed
+    pending: [[u64; ROLLBACK_INDEX_LOCATIONS]; ROLLBACK_INDEX_SLOTS],
+}
+
+impl RollbackIndexes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read the indexes from the misc storage. A blank store reads as zero
+    /// indexes, a corrupted one is an InvalidData error.
+    pub fn load(storage: &dyn MiscStorage) -> Result<Self, std::io::Error> {
+        let mut buffer = [0u8; ROLLBACK_INDEX_STORE_SIZE];
+        storage.read_at(&mut buffer, ROLLBACK_INDEX_OFFSET_IN_MISC as u64)?;
+        if buffer.iter().all(|b| *b == 0x00) || buffer.iter().all(|b| *b == 0xff) {
+            log::info!("Rollback index store is blank, starting with zero indexes");
+            return Ok(Self::new());
+        }
+        Self::from_bytes(&buffer)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    /// Write the indexes into the misc storage
+    pub fn save(&self, storage: &mut dyn MiscStorage) -> Result<(), std::io::Error> {
+        storage.write_verified(&self.to_bytes(), ROLLBACK_INDEX_OFFSET_IN_MISC as u64)
+    }
+
+    /// Parse the indexes from their serialized form
+    pub fn from_bytes(data: &[u8]) -> Result<Self, BootloaderMessageError> {
+        if data.len() < HEADER_SIZE + PAYLOAD_SIZE {
+            return Err(BootloaderMessageError::InsufficientBytes);
+        }
+        if data[0..4] != ROLLBACK_INDEX_MAGIC {
+            return Err(BootloaderMessageError::InvalidMagic);
+        }
+        if data[4] != ROLLBACK_INDEX_VERSION {
+            return Err(BootloaderMessageError::UnsupportedVersion);
+        }
+        if read_u32(&data[8..12]) as usize != PAYLOAD_SIZE {
+            return Err(BootloaderMessageError::UnsupportedVersion);
+        }
+
+        let payload = &data[HEADER_SIZE..HEADER_SIZE + PAYLOAD_SIZE];
+        let algo = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+        if algo.checksum(payload) != read_u32(&data[12..16]) {
+            return Err(BootloaderMessageError::CrcFailure);
+        }
+
+        let mut values = payload.chunks_exact(8).map(read_u64);
+        let mut indexes = Self::new();
+        for index in indexes.rollback_index.iter_mut() {
+            *index = values.next().unwrap_or_default();
+        }
+        for slot in indexes.pending.iter_mut() {
+            for index in slot.iter_mut() {
+                *index = values.next().unwrap_or_default();
+            }
+        }
+        Ok(indexes)
+    }
+
+    /// Serialize the indexes. The result is padded to the full size of the store.
+    pub fn to_bytes(&self) -> [u8; ROLLBACK_INDEX_STORE_SIZE] {
+        let mut payload = Vec::with_capacity(PAYLOAD_SIZE);
+        for index in self.rollback_index.iter() {
+            payload.extend_from_slice(&index.to_le_bytes());
+        }
+        for slot in self.pending.iter() {
+            for index in slot.iter() {
+                payload.extend_from_slice(&index.to_le_bytes());
+            }
+        }
+
+        let algo = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+        let mut data = [0u8; ROLLBACK_INDEX_STORE_SIZE];
+        data[0..4].copy_from_slice(&ROLLBACK_INDEX_MAGIC);
+        data[4] = ROLLBACK_INDEX_VERSION;
+        data[8..12].copy_from_slice(&(PAYLOAD_SIZE as u32).to_le_bytes());
+        data[12..16].copy_from_slice(&algo.checksum(&payload).to_le_bytes());
+        data[HEADER_SIZE..HEADER_SIZE + PAYLOAD_SIZE].copy_from_slice(&payload);
+        data
+    }
+
+    /// The stored rollback index of the location
+    pub fn rollback_index(&self, location: usize) -> Result<u64, BootloaderMessageError> {
+        self.rollback_index
+            .get(location)
+            .copied()
+            .ok_or(BootloaderMessageError::InvalidRollbackIndexLocation)
+    }
+
+    /// The rollback index staged for the slot, 0 if none is staged
+    pub fn pending_rollback_index(
+        &self,
+        slot_index: usize,
+        location: usize,
+    ) -> Result<u64, BootloaderMessageError> {
+        self.pending
+            .get(slot_index)
+            .ok_or(BootloaderMessageError::InvalidSlotCount)?
+            .get(location)
+            .copied()
+            .ok_or(BootloaderMessageError::InvalidRollbackIndexLocation)
+    }
+
+    /// Check the rollback index of an image against the stored one
+    pub fn check(&self, location: usize, index: u64) -> Result<(), BootloaderMessageError> {
+        if index < self.rollback_index(location)? {
+            Err(BootloaderMessageError::RollbackIndexTooLow)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Check the rollback index of the image the slot booted and stage it.
+    /// The stored index is not changed until the slot is committed.
+    pub fn stage(
+        &mut self,
+        slot_index: usize,
+        location: usize,
+        index: u64,
+    ) -> Result<(), BootloaderMessageError> {
+        self.check(location, index)?;
+        let pending = self
+            .pending
+            .get_mut(slot_index)
+            .ok_or(BootloaderMessageError::InvalidSlotCount)?;
+        pending[location] = index;
+        Ok(())
+    }
+
+    /// Raise the stored indexes to the ones staged for the slot. The stored
+    /// indexes never decrease. Returns true if anything changed.
+    pub fn commit(&mut self, slot_index: usize) -> Result<bool, BootloaderMessageError> {
+        let pending = self
+            .pending
+            .get_mut(slot_index)
+            .ok_or(BootloaderMessageError::InvalidSlotCount)?;
+
+        let mut changed = false;
+        for (stored, staged) in self.rollback_index.iter_mut().zip(pending.iter_mut()) {
+            if *staged > *stored {
+                *stored = *staged;
+                changed = true;
+            }
+            if *staged != 0 {
+                *staged = 0;
+                changed = true;
+            }
+        }
+        Ok(changed)
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut value = [0u8; 8];
+    value.copy_from_slice(&bytes[0..8]);
+    u64::from_le_bytes(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bootloader::storage::MemoryStorage;
+
+    #[test]
+    fn raise_only_on_commit() {
+        let mut storage = MemoryStorage::new(64 * 1024);
+        assert_eq!(
+            RollbackIndexes::load(&storage).unwrap(),
+            RollbackIndexes::new()
+        );
+
+        let mut indexes = RollbackIndexes::new();
+        indexes.stage(1, VERITY_ROLLBACK_INDEX_LOCATION, 5).unwrap();
+        assert_eq!(
+            indexes
+                .rollback_index(VERITY_ROLLBACK_INDEX_LOCATION)
+                .unwrap(),
+            0
+        );
+        indexes.save(&mut storage).unwrap();
+
+        // nothing else in the vendor space is touched
+        assert_eq!(
+            &storage.as_slice()[0..ROLLBACK_INDEX_OFFSET_IN_MISC],
+            &[0u8; ROLLBACK_INDEX_OFFSET_IN_MISC][..]
+        );
+
+        let mut indexes = RollbackIndexes::load(&storage).unwrap();
+        assert_eq!(indexes.pending_rollback_index(1, 0).unwrap(), 5);
+        // slot 0 booted again instead, nothing is raised
+        assert!(!indexes.commit(0).unwrap());
+        assert!(indexes.commit(1).unwrap());
+        assert_eq!(indexes.rollback_index(0).unwrap(), 5);
+        assert_eq!(indexes.pending_rollback_index(1, 0).unwrap(), 0);
+
+        // an older image is refused, the index never decreases
+        assert!(matches!(
+            indexes.stage(0, 0, 4),
+            Err(BootloaderMessageError::RollbackIndexTooLow)
+        ));
+        indexes.stage(0, 0, 5).unwrap();
+        indexes.commit(0).unwrap();
+        assert_eq!(indexes.rollback_index(0).unwrap(), 5);
+
+        assert!(matches!(
+            indexes.rollback_index(ROLLBACK_INDEX_LOCATIONS),
+            Err(BootloaderMessageError::InvalidRollbackIndexLocation)
+        ));
+        assert!(indexes.stage(4, 0, 6).is_err());
+    }
+
+    #[test]
+    fn detect_corruption() {
+        let mut indexes = RollbackIndexes::new();
+        indexes.stage(0, 2, 0x0102_0304).unwrap();
+        indexes.commit(0).unwrap();
+        let mut data = indexes.to_bytes();
+        assert_eq!(RollbackIndexes::from_bytes(&data).unwrap(), indexes);
+
+        data[HEADER_SIZE + 16] ^= 0x01;
+        assert!(matches!(
+            RollbackIndexes::from_bytes(&data),
+            Err(BootloaderMessageError::CrcFailure)
+        ));
+
+        // only a blank store reads as zero indexes
+        let mut storage = MemoryStorage::new(64 * 1024);
+        storage
+            .write_at(&data, ROLLBACK_INDEX_OFFSET_IN_MISC as u64)
+            .unwrap();
+        let error = RollbackIndexes::load(&storage).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        storage
+            .write_at(&[0xa5; 4], ROLLBACK_INDEX_OFFSET_IN_MISC as u64)
+            .unwrap();
+        assert!(RollbackIndexes::load(&storage).is_err());
+
+        storage
+            .write_at(
+                &[0xff; ROLLBACK_INDEX_STORE_SIZE],
+                ROLLBACK_INDEX_OFFSET_IN_MISC as u64,
+            )
+            .unwrap();
+        assert_eq!(
+            RollbackIndexes::load(&storage).unwrap(),
+            RollbackIndexes::new()
+        );
+    }
+}
@@ -0,0 +1,213 @@
+/*
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+/*
+  The bootloader side of the A/B scheme. This mirrors ab_select_slot() from
+  U-Boot (boot/android_ab.c) so that boot, crash and rollback sequences can be
+  simulated on the host against the same BootloaderControl structure that the
+  bootloader operates on.
+*/
+
+use std::cmp::Ordering;
+
+use super::bootcontrol::slot_suffix_from_index;
+use super::error::BootloaderMessageError;
+use super::message::{BootloaderControl, SlotMetadata};
+
+impl SlotMetadata {
+    /// The bootloader skips a slot that dm-verity found corrupted or that has
+    /// no tries remaining. The priority only orders the bootable slots.
+    pub fn is_bootable(&self) -> bool {
+        self.verity_corrupted() == 0 && self.tries_remaining() > 0
+    }
+
+    /// Order slots by preference. The preferred slot is Less.
+    /// A higher priority wins, then a successful boot, then more tries remaining
+    /// so that slots with equal priority are tried in a round-robin way.
+    fn compare_preference(&self, other: &SlotMetadata) -> Ordering {
+        other
+            .priority()
+            .cmp(&self.priority())
+            .then(other.successful_boot().cmp(&self.successful_boot()))
+            .then(other.tries_remaining().cmp(&self.tries_remaining()))
+    }
+}
+
+impl BootloaderControl {
+    /// Select the slot to boot, the way the bootloader does it.
+    ///
+    /// The bootable slot with the highest priority is selected. If the slot
+    /// has not booted successfully yet, one of its tries is used up. The
+    /// slot suffix is updated to the selected slot. The structure is modified
+    /// in place and must be stored back to the misc partition by the caller.
+    pub fn select_slot(&mut self) -> Result<usize, BootloaderMessageError> {
+        let mut selected: Option<usize> = None;
+
+        for index in 0..self.slot_count() {
+            let slot = self.slot_info[index];
+            if !slot.is_bootable() {
+                log::debug!("Slot {} is unbootable", index);
+                continue;
+            }
+            selected = match selected {
+                Some(current)
+                    if slot.compare_preference(&self.slot_info[current]) != Ordering::Less =>
+                {
+                    Some(current)
+                }
+                _ => Some(index),
+            };
+        }
+
+        let slot = selected.ok_or(BootloaderMessageError::NoBootableSlot)?;
+        let metadata = &mut self.slot_info[slot];
+        if metadata.successful_boot() == 0 {
+            let tries = metadata.tries_remaining();
+            metadata.set_tries_remaining(tries - 1);
+        }
+
+        // Legacy user-space reads the slot suffix from here
+        let suffix =
+            slot_suffix_from_index(slot).map_err(|_| BootloaderMessageError::InvalidSlotIndex)?;
+        self.set_slot_suffix(suffix)?;
+
+        Ok(slot)
+    }
+
+    /// Use up one recovery try, the way the bootloader does it on every boot
+    /// into recovery. Returns the tries remaining after this boot.
+    pub fn use_recovery_try(&mut self) -> Result<u8, BootloaderMessageError> {
+        match self.recovery_tries_remaining() {
+            0 => Err(BootloaderMessageError::RecoveryTriesExhausted),
+            tries => {
+                self.set_recovery_tries_remaining(tries - 1);
+                Ok(tries - 1)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bootloader::message::BootloaderMessageAB;
+
+    fn control_from_testdata() -> BootloaderControl {
+        let json = include_str!("./testdata/bolomessage.json");
+        let bolo_message_ab = BootloaderMessageAB::from_json(json).unwrap();
+        bolo_message_ab.get_bootloader_control().unwrap()
+    }
+
+    #[test]
+    fn round_robin_between_equal_slots() {
+        let mut ctrl = control_from_testdata();
+
+        // b has more tries remaining
+        assert_eq!(ctrl.select_slot().unwrap(), 1);
+        assert_eq!(ctrl.slot_info[1].tries_remaining(), 6);
+        assert_eq!(ctrl.slot_suffix().unwrap().to_str().unwrap(), "b");
+
+        // both have 6 tries now, the first slot wins
+        assert_eq!(ctrl.select_slot().unwrap(), 0);
+        assert_eq!(ctrl.slot_info[0].tries_remaining(), 5);
+        assert_eq!(ctrl.slot_suffix().unwrap().to_str().unwrap(), "a");
+    }
+
+    #[test]
+    fn successful_slot_keeps_its_tries() {
+        let mut ctrl = control_from_testdata();
+        ctrl.slot_info[0].set_successful_boot(1);
+
+        for _ in 0..10 {
+            assert_eq!(ctrl.select_slot().unwrap(), 0);
+        }
+        assert_eq!(ctrl.slot_info[0].tries_remaining(), 6);
+    }
+
+    #[test]
+    fn fallback_when_tries_run_out() {
+        let mut ctrl = control_from_testdata();
+        // a is the old, known good slot. b was just updated
+        ctrl.slot_info[0].set_priority(14);
+        ctrl.slot_info[0].set_successful_boot(1);
+        ctrl.slot_info[1].set_priority(15);
+        ctrl.slot_info[1].set_tries_remaining(3);
+
+        // b crashes on every boot
+        for tries_left in (0..3).rev() {
+            assert_eq!(ctrl.select_slot().unwrap(), 1);
+            assert_eq!(ctrl.slot_info[1].tries_remaining(), tries_left);
+        }
+        assert!(!ctrl.slot_info[1].is_bootable());
+
+        // roll back to a
+        assert_eq!(ctrl.select_slot().unwrap(), 0);
+        assert_eq!(ctrl.slot_suffix().unwrap().to_str().unwrap(), "a");
+    }
+
+    #[test]
+    fn recovery_tries() {
+        let mut ctrl = control_from_testdata();
+        ctrl.set_recovery_tries_remaining(2);
+
+        assert_eq!(ctrl.use_recovery_try().unwrap(), 1);
+        assert_eq!(ctrl.use_recovery_try().unwrap(), 0);
+        assert!(matches!(
+            ctrl.use_recovery_try(),
+            Err(BootloaderMessageError::RecoveryTriesExhausted)
+        ));
+    }
+
+    #[test]
+    fn no_bootable_slot() {
+        let mut ctrl = control_from_testdata();
+        ctrl.slot_info[0].set_tries_remaining(0);
+        // a successful slot without tries is skipped as well
+        ctrl.slot_info[1].set_successful_boot(1);
+        ctrl.slot_info[1].set_tries_remaining(0);
+
+        assert!(matches!(
+            ctrl.select_slot(),
+            Err(BootloaderMessageError::NoBootableSlot)
+        ));
+    }
+
+    #[test]
+    fn skip_verity_corrupted_slot() {
+        let mut ctrl = control_from_testdata();
+        // the corrupted slot has the highest priority and has booted before
+        ctrl.slot_info[0].set_priority(14);
+        ctrl.slot_info[1].set_successful_boot(1);
+        ctrl.slot_info[1].set_verity_corrupted(1);
+
+        assert!(!ctrl.slot_info[1].is_bootable());
+        assert_eq!(ctrl.select_slot().unwrap(), 0);
+        assert_eq!(ctrl.slot_suffix().unwrap().to_str().unwrap(), "a");
+    }
+
+    #[test]
+    fn skip_last_verity_corrupted_slot() {
+        let mut ctrl = control_from_testdata();
+        ctrl.set_slot_verity_corrupted(1).unwrap();
+        assert_eq!(ctrl.slot_info[1].tries_remaining(), 0);
+
+        // the last bootable slot keeps its tries, the bootloader still skips it
+        ctrl.set_slot_verity_corrupted(0).unwrap();
+        assert_eq!(ctrl.slot_info[0].tries_remaining(), 6);
+        assert!(matches!(
+            ctrl.select_slot(),
+            Err(BootloaderMessageError::NoBootableSlot)
+        ));
+    }
+}
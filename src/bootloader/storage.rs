@@ -0,0 +1,234 @@
+/*
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+use std::{
+    convert::TryFrom,
+    ffi::CString,
+    fs::File,
+    io::{Error, ErrorKind},
+    os::unix::prelude::{AsRawFd, FileExt},
+    path::Path,
+};
+
+use crate::{
+    mount::early_partitions::{ensure_mount_device_is_created, MISC_PARTITION_NAME},
+    uevent::create_and_bind_netlink_socket,
+};
+
+/// Backing storage for the contents of the misc partition. Offsets are
+/// relative to the start of the misc partition.
+pub trait MiscStorage {
+    /// Fill the buffer with the bytes at the offset.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error>;
+    /// Write all of the buffer at the offset.
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error>;
+    /// Make sure that the written data has reached the storage.
+    fn sync(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    /// Drop the cached data of the range, so that the next read comes from
+    /// the storage itself.
+    fn drop_cache(&mut self, _offset: u64, _len: usize) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    /// Write the buffer, sync it to the storage and read it back from the
+    /// storage to verify that the stored data matches.
+    fn write_verified(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        self.write_at(buf, offset)?;
+        self.sync()?;
+        self.drop_cache(offset, buf.len())?;
+
+        let mut read_back = vec![0u8; buf.len()];
+        self.read_at(&mut read_back, offset)?;
+        if read_back != buf {
+            log::error!("Verification of misc write at offset {} failed", offset);
+            Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Verification of the write at offset {} failed", offset),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// The misc partition on a block device. The device entry is
+/// created from the uevents if it does not exist yet.
+pub struct BlockDeviceStorage(File);
+
+impl BlockDeviceStorage {
+    pub fn open(device: &Path) -> Result<Self, std::io::Error> {
+        let mut nl_socket = create_and_bind_netlink_socket()?;
+        let device_name = CString::new(
+            device
+                .to_str()
+                .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Invalid device path"))?,
+        )?;
+        ensure_mount_device_is_created(&device_name, &mut nl_socket)?;
+
+        let handle = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(device)?;
+        Ok(Self(handle))
+    }
+
+    /// Open the misc partition of this device
+    pub fn misc() -> Result<Self, std::io::Error> {
+        Self::open(Path::new(MISC_PARTITION_NAME))
+    }
+}
+
+impl MiscStorage for BlockDeviceStorage {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+        self.0.read_exact_at(buf, offset)
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        self.0.write_all_at(buf, offset)
+    }
+
+    fn sync(&mut self) -> Result<(), std::io::Error> {
+        self.0.sync_data()
+    }
+
+    fn drop_cache(&mut self, offset: u64, len: usize) -> Result<(), std::io::Error> {
+        drop_page_cache(&self.0, offset, len)
+    }
+}
+
+/// Drop the pages of the range from the page cache. The data must be synced first.
+fn drop_page_cache(file: &File, offset: u64, len: usize) -> Result<(), std::io::Error> {
+    let offset = libc::off_t::try_from(offset)
+        .map_err(|_e| Error::new(ErrorKind::InvalidInput, "Offset out of range"))?;
+    let len = libc::off_t::try_from(len)
+        .map_err(|_e| Error::new(ErrorKind::InvalidInput, "Length out of range"))?;
+    // posix_fadvise returns the error number instead of setting errno
+    let ret =
+        unsafe { libc::posix_fadvise(file.as_raw_fd(), offset, len, libc::POSIX_FADV_DONTNEED) };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(Error::from_raw_os_error(ret))
+    }
+}
+
+/// A misc partition image in a regular file. Useful on the host and in tests.
+pub struct FileStorage(File);
+
+impl FileStorage {
+    /// Open an existing image file
+    pub fn open(path: &Path) -> Result<Self, std::io::Error> {
+        let handle = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)?;
+        Ok(Self(handle))
+    }
+
+    /// Create a new, zero filled image file of the given size. An existing file is overwritten.
+    pub fn create(path: &Path, size: u64) -> Result<Self, std::io::Error> {
+        let handle = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        handle.set_len(size)?;
+        Ok(Self(handle))
+    }
+}
+
+impl MiscStorage for FileStorage {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+        self.0.read_exact_at(buf, offset)
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        self.0.write_all_at(buf, offset)
+    }
+
+    fn sync(&mut self) -> Result<(), std::io::Error> {
+        self.0.sync_data()
+    }
+
+    fn drop_cache(&mut self, offset: u64, len: usize) -> Result<(), std::io::Error> {
+        drop_page_cache(&self.0, offset, len)
+    }
+}
+
+/// A misc partition held in memory. The size is fixed at creation.
+pub struct MemoryStorage(Vec<u8>);
+
+impl MemoryStorage {
+    /// Create a zero filled storage of the given size
+    pub fn new(size: usize) -> Self {
+        Self(vec![0; size])
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        self.0.as_slice()
+    }
+
+    fn range(&self, offset: u64, len: usize) -> Result<std::ops::Range<usize>, std::io::Error> {
+        let start = usize::try_from(offset)
+            .map_err(|_e| Error::new(ErrorKind::UnexpectedEof, "Offset out of range"))?;
+        match start.checked_add(len) {
+            Some(end) if end <= self.0.len() => Ok(start..end),
+            _ => Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Access beyond the end of the storage",
+            )),
+        }
+    }
+}
+
+impl From<Vec<u8>> for MemoryStorage {
+    fn from(data: Vec<u8>) -> Self {
+        Self(data)
+    }
+}
+
+impl MiscStorage for MemoryStorage {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+        let range = self.range(offset, buf.len())?;
+        buf.copy_from_slice(&self.0[range]);
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        let range = self.range(offset, buf.len())?;
+        self.0[range].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+/// A misc partition in memory that holds the bootloader message of the test data
+#[cfg(test)]
+pub fn misc_from_testdata() -> MemoryStorage {
+    let json = include_str!("./testdata/bolomessage.json");
+    let message = super::message::BootloaderMessageAB::from_json(json).unwrap();
+    let mut storage = MemoryStorage::new(64 * 1024);
+    storage.write_at(&message.to_bytes(), 0).unwrap();
+    storage
+}
+
+/// A path in the temp directory that is unique to the test process, so that
+/// test runs in parallel do not share files
+#[cfg(test)]
+pub fn temp_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("{}_{}", std::process::id(), name))
+}
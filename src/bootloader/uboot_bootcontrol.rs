@@ -0,0 +1,368 @@
+/*
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+/*
+  Boot control for boards that keep the slot state in the U-Boot environment,
+  using the RAUC boot counting scheme:
+
+  BOOT_ORDER   the slots in the order they are tried, for example "A B"
+  BOOT_x_LEFT  the boot attempts left for slot x
+
+  The boot script tries the slots in BOOT_ORDER and boots the first one with
+  attempts left, decrementing its BOOT_x_LEFT. Slot 0 is "A", slot 1 is "B"
+  and so on. The slots are the ones that have a BOOT_x_LEFT variable, a slot
+  that was dropped from BOOT_ORDER is still a slot.
+
+  The scheme has no flag for a successful boot, so BOOT_x_SUCCESSFUL is kept
+  for that. The boot script does not need to know about it.
+*/
+
+use std::io::{Error, ErrorKind};
+
+use sabaton_hal::bootloader::{get_slot_suffix_from_cmd_line, BootControl};
+
+use super::bootcontrol::{slot_index_from_suffix, VerifiedBootControl};
+use super::uboot_env::UBootEnvironment;
+
+pub const BOOT_ORDER_VARIABLE: &str = "BOOT_ORDER";
+/// Boot attempts given to a slot when it is made active or marked successful
+pub const DEFAULT_BOOT_ATTEMPTS: u32 = 3;
+/// Kernel command line parameter the RAUC boot script uses for the booted slot
+const RAUC_SLOT_PARAMETER: &str = "rauc.slot=";
+const SLOT_NAMES: [&str; 4] = ["A", "B", "C", "D"];
+
+pub struct UBootBootControl {
+    env: UBootEnvironment,
+    boot_attempts: u32,
+}
+
+impl UBootBootControl {
+    /// Create the boot control over the environment described by /etc/fw_env.config
+    pub fn create() -> Result<Self, std::io::Error> {
+        Ok(Self::create_from_environment(UBootEnvironment::open()?))
+    }
+
+    /// Create the boot control over any environment, for example an image file
+    pub fn create_from_environment(env: UBootEnvironment) -> Self {
+        Self {
+            env,
+            boot_attempts: DEFAULT_BOOT_ATTEMPTS,
+        }
+    }
+
+    /// Set the boot attempts given to a slot when it is made active or marked successful
+    pub fn set_boot_attempts(&mut self, boot_attempts: u32) {
+        self.boot_attempts = boot_attempts;
+    }
+
+    /// Write the variables for the number of slots. All slots get the default
+    /// boot attempts and the first slot is made active.
+    pub fn initialize_metadata(&mut self, number_of_slots: usize) -> Result<(), std::io::Error> {
+        if number_of_slots == 0 || number_of_slots > SLOT_NAMES.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid number of slots : {}", number_of_slots),
+            ));
+        }
+        let names = &SLOT_NAMES[0..number_of_slots];
+        self.env.set(BOOT_ORDER_VARIABLE, &names.join(" "))?;
+        for name in names {
+            self.env
+                .set(&boot_left_variable(name), &self.boot_attempts.to_string())?;
+            self.env.remove(&boot_successful_variable(name));
+        }
+        for name in &SLOT_NAMES[number_of_slots..] {
+            self.env.remove(&boot_left_variable(name));
+            self.env.remove(&boot_successful_variable(name));
+        }
+        self.env.save()
+    }
+
+    /// The environment that holds the slot state
+    pub fn environment(&self) -> &UBootEnvironment {
+        &self.env
+    }
+
+    fn boot_order(&self) -> Result<Vec<usize>, std::io::Error> {
+        let boot_order = self.env.get(BOOT_ORDER_VARIABLE).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{} is not set", BOOT_ORDER_VARIABLE),
+            )
+        })?;
+        boot_order
+            .split_whitespace()
+            .map(slot_index_from_name)
+            .collect()
+    }
+
+    fn set_boot_order(&mut self, boot_order: &[usize]) -> Result<(), std::io::Error> {
+        let names: Vec<&str> = boot_order.iter().map(|index| SLOT_NAMES[*index]).collect();
+        self.env.set(BOOT_ORDER_VARIABLE, &names.join(" "))
+    }
+
+    fn boot_left(&self, slot_index: usize) -> Result<u32, std::io::Error> {
+        let name = boot_left_variable(SLOT_NAMES[slot_index]);
+        match self.env.get(&name) {
+            Some(value) => value.trim().parse().map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{}={} : {}", name, value, e),
+                )
+            }),
+            None => Ok(0),
+        }
+    }
+
+    /// The number of slots, the slots from A on that have a BOOT_x_LEFT variable.
+    /// The slots must not have gaps.
+    fn slot_count(&self) -> Result<usize, std::io::Error> {
+        let count = SLOT_NAMES
+            .iter()
+            .take_while(|name| self.env.get(&boot_left_variable(name)).is_some())
+            .count();
+        if let Some(name) = SLOT_NAMES[count..]
+            .iter()
+            .find(|name| self.env.get(&boot_left_variable(name)).is_some())
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{} is set but slot {} has no boot attempts variable",
+                    boot_left_variable(name),
+                    SLOT_NAMES[count]
+                ),
+            ));
+        }
+        Ok(count)
+    }
+
+    /// Check that the slot has a BOOT_x_LEFT variable
+    fn check_slot_index(&self, slot_index: usize) -> Result<(), std::io::Error> {
+        if slot_index < self.slot_count()? {
+            Ok(())
+        } else {
+            Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid slot index : {}", slot_index),
+            ))
+        }
+    }
+}
+
+fn boot_left_variable(slot_name: &str) -> String {
+    format!("BOOT_{}_LEFT", slot_name)
+}
+
+fn boot_successful_variable(slot_name: &str) -> String {
+    format!("BOOT_{}_SUCCESSFUL", slot_name)
+}
+
+fn slot_index_from_name(name: &str) -> Result<usize, std::io::Error> {
+    SLOT_NAMES.iter().position(|n| *n == name).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid slot name : {}", name),
+        )
+    })
+}
+
+impl BootControl for UBootBootControl {
+    fn number_of_slots(&self) -> Result<usize, std::io::Error> {
+        self.slot_count()
+    }
+
+    /// Get the current slot from the kernel command line. The rauc.slot parameter
+    /// is used if present, the Android slot suffix otherwise.
+    fn current_slot(&self) -> Result<usize, std::io::Error> {
+        let command_line = std::fs::read_to_string("/proc/cmdline")?;
+        match command_line
+            .split_whitespace()
+            .find_map(|p| p.strip_prefix(RAUC_SLOT_PARAMETER))
+        {
+            Some(name) => slot_index_from_name(name),
+            None => slot_index_from_suffix(get_slot_suffix_from_cmd_line(&command_line)?),
+        }
+    }
+
+    fn set_boot_successful(&mut self) -> Result<(), std::io::Error> {
+        let current_slot = self.current_slot()?;
+        self.check_slot_index(current_slot)?;
+
+        let name = SLOT_NAMES[current_slot];
+        self.env
+            .set(&boot_left_variable(name), &self.boot_attempts.to_string())?;
+        self.env.set(&boot_successful_variable(name), "1")?;
+        self.env.save()
+    }
+
+    /// Move the slot to the front of BOOT_ORDER and give it fresh boot attempts
+    fn set_active_slot(&mut self, slot_index: usize) -> Result<(), std::io::Error> {
+        self.check_slot_index(slot_index)?;
+
+        let mut boot_order = self.boot_order()?;
+        boot_order.retain(|index| *index != slot_index);
+        boot_order.insert(0, slot_index);
+        self.set_boot_order(&boot_order)?;
+
+        let name = SLOT_NAMES[slot_index];
+        self.env
+            .set(&boot_left_variable(name), &self.boot_attempts.to_string())?;
+        self.env.remove(&boot_successful_variable(name));
+        self.env.save()
+    }
+
+    fn set_slot_as_unbootable(&mut self, slot_index: usize) -> Result<(), std::io::Error> {
+        self.check_slot_index(slot_index)?;
+
+        let name = SLOT_NAMES[slot_index];
+        self.env.set(&boot_left_variable(name), "0")?;
+        self.env.remove(&boot_successful_variable(name));
+        self.env.save()
+    }
+
+    fn is_bootable(&self, slot_index: usize) -> Result<bool, std::io::Error> {
+        self.check_slot_index(slot_index)?;
+        Ok(self.boot_left(slot_index)? > 0)
+    }
+
+    fn is_slot_successful(&self, slot_index: usize) -> Result<bool, std::io::Error> {
+        self.check_slot_index(slot_index)?;
+        Ok(self
+            .env
+            .get(&boot_successful_variable(SLOT_NAMES[slot_index]))
+            == Some("1"))
+    }
+
+    /// The first slot in BOOT_ORDER that has boot attempts left
+    fn active_slot(&self) -> Result<usize, std::io::Error> {
+        for slot_index in self.boot_order()? {
+            if self.boot_left(slot_index)? > 0 {
+                return Ok(slot_index);
+            }
+        }
+        Err(Error::new(ErrorKind::InvalidData, "No bootable slot"))
+    }
+}
+
+/// The U-Boot environment has no verity corrupted flag, a corrupted slot is
+/// only made unbootable.
+impl VerifiedBootControl for UBootBootControl {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bootloader::storage::{temp_path, FileStorage, MemoryStorage, MiscStorage};
+
+    const ENV_SIZE: usize = 0x4000;
+
+    fn open_image(path: &std::path::Path) -> UBootEnvironment {
+        let copies: Vec<(Box<dyn MiscStorage>, u64)> = vec![
+            (Box::new(FileStorage::open(path).unwrap()), 0),
+            (Box::new(FileStorage::open(path).unwrap()), ENV_SIZE as u64),
+        ];
+        UBootEnvironment::load(copies, ENV_SIZE).unwrap()
+    }
+
+    #[test]
+    fn boot_control_over_env_image() {
+        let path = temp_path("uboot_boot_control_over_env_image.img");
+        let copies: Vec<(Box<dyn MiscStorage>, u64)> = vec![
+            (
+                Box::new(FileStorage::create(&path, 2 * ENV_SIZE as u64).unwrap()),
+                0,
+            ),
+            (Box::new(FileStorage::open(&path).unwrap()), ENV_SIZE as u64),
+        ];
+        let env = UBootEnvironment::create(copies, ENV_SIZE).unwrap();
+        let mut boot_control = UBootBootControl::create_from_environment(env);
+        boot_control.initialize_metadata(2).unwrap();
+
+        let mut boot_control = UBootBootControl::create_from_environment(open_image(&path));
+        assert_eq!(boot_control.number_of_slots().unwrap(), 2);
+        assert_eq!(boot_control.active_slot().unwrap(), 0);
+        assert!(boot_control.is_bootable(1).unwrap());
+        assert!(!boot_control.is_slot_successful(0).unwrap());
+
+        boot_control.set_active_slot(1).unwrap();
+        assert!(boot_control.set_active_slot(2).is_err());
+
+        let mut boot_control = UBootBootControl::create_from_environment(open_image(&path));
+        assert_eq!(
+            boot_control.environment().get(BOOT_ORDER_VARIABLE),
+            Some("B A")
+        );
+        assert_eq!(boot_control.active_slot().unwrap(), 1);
+
+        // the bootloader used up the attempts of B, fall back to A
+        boot_control.set_slot_as_unbootable(1).unwrap();
+        assert!(!boot_control.is_bootable(1).unwrap());
+        assert_eq!(boot_control.active_slot().unwrap(), 0);
+
+        boot_control.set_slot_as_unbootable(0).unwrap();
+        assert!(boot_control.active_slot().is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn invalid_variables() {
+        let boot_control_with = |boot_order: &str, boot_a_left: &str| {
+            let copies: Vec<(Box<dyn MiscStorage>, u64)> =
+                vec![(Box::new(MemoryStorage::new(ENV_SIZE)), 0)];
+            let mut env = UBootEnvironment::create(copies, ENV_SIZE).unwrap();
+            env.set(BOOT_ORDER_VARIABLE, boot_order).unwrap();
+            env.set("BOOT_A_LEFT", boot_a_left).unwrap();
+            UBootBootControl::create_from_environment(env)
+        };
+
+        assert!(boot_control_with("A X", "3").active_slot().is_err());
+
+        let boot_control = boot_control_with("A B", "many");
+        assert_eq!(boot_control.number_of_slots().unwrap(), 1);
+        assert!(boot_control.is_bootable(0).is_err());
+        assert!(boot_control.is_bootable(1).is_err());
+
+        // a gap in the slots
+        let mut boot_control = boot_control_with("A", "3");
+        boot_control.env.set("BOOT_C_LEFT", "3").unwrap();
+        assert!(boot_control.number_of_slots().is_err());
+    }
+
+    #[test]
+    fn slots_from_boot_attempts() {
+        let copies: Vec<(Box<dyn MiscStorage>, u64)> =
+            vec![(Box::new(MemoryStorage::new(ENV_SIZE)), 0)];
+        let mut env = UBootEnvironment::create(copies, ENV_SIZE).unwrap();
+        env.set(BOOT_ORDER_VARIABLE, "A").unwrap();
+        env.set("BOOT_A_LEFT", "3").unwrap();
+        env.set("BOOT_B_LEFT", "0").unwrap();
+        let mut boot_control = UBootBootControl::create_from_environment(env);
+
+        // B is not in BOOT_ORDER but it is still a slot
+        assert_eq!(boot_control.number_of_slots().unwrap(), 2);
+        assert!(!boot_control.is_bootable(1).unwrap());
+        boot_control.set_active_slot(1).unwrap();
+        assert_eq!(
+            boot_control.environment().get(BOOT_ORDER_VARIABLE),
+            Some("B A")
+        );
+        assert_eq!(boot_control.active_slot().unwrap(), 1);
+        assert!(boot_control.set_active_slot(2).is_err());
+
+        boot_control.initialize_metadata(1).unwrap();
+        assert_eq!(boot_control.number_of_slots().unwrap(), 1);
+        assert!(boot_control.environment().get("BOOT_B_LEFT").is_none());
+    }
+}
@@ -0,0 +1,462 @@
+/*
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+/*
+  Access to the U-Boot environment, compatible with fw_printenv/fw_setenv.
+
+  The environment is a block of "name=value\0" strings terminated by an
+  empty string. The block is preceded by a little endian CRC32 of the data.
+  With a redundant environment, there are two copies and each copy has an
+  additional flags byte after the CRC. The copy with the newer flags value
+  is the active one, and writes go to the other copy.
+
+  Only block devices and files are supported. An MTD device has to be erased
+  before it is written, which is not done here.
+*/
+
+use std::{
+    collections::BTreeMap,
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+use crc::{Crc, CRC_32_ISO_HDLC};
+
+use super::storage::{BlockDeviceStorage, FileStorage, MiscStorage};
+
+/// The default location of the configuration of the environment
+pub const FW_ENV_CONFIG_LOCATION: &str = "/etc/fw_env.config";
+
+/// One line of fw_env.config. Describes where a copy of the environment is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FwEnvLocation {
+    /// Device or file holding the environment
+    pub device: PathBuf,
+    /// Offset of the environment within the device
+    pub offset: u64,
+    /// Size of the environment
+    pub size: usize,
+    /// Size of an erase sector, only used by MTD devices
+    pub sector_size: Option<u64>,
+    /// Number of erase sectors of the environment, only used by MTD devices
+    pub sector_count: Option<u64>,
+}
+
+/// Parse a fw_env.config file. One or two locations are expected, two
+/// locations mean a redundant environment.
+pub fn parse_fw_env_config(contents: &str) -> Result<Vec<FwEnvLocation>, Error> {
+    let mut locations = Vec::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.len() < 3 || parts.len() > 5 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid fw_env.config line : {}", line),
+            ));
+        }
+        locations.push(FwEnvLocation {
+            device: PathBuf::from(parts[0]),
+            offset: parse_number(parts[1])?,
+            size: parse_number(parts[2])? as usize,
+            sector_size: parts.get(3).map(|s| parse_number(s)).transpose()?,
+            sector_count: parts.get(4).map(|s| parse_number(s)).transpose()?,
+        });
+    }
+
+    match locations.len() {
+        1 | 2 => {
+            if locations.len() == 2 && locations[0].size != locations[1].size {
+                Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Redundant environments must have the same size",
+                ))
+            } else {
+                Ok(locations)
+            }
+        }
+        n => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Expected one or two environments, found {}", n),
+        )),
+    }
+}
+
+fn parse_number(s: &str) -> Result<u64, Error> {
+    let parsed = if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        u64::from_str_radix(hex, 16)
+    } else {
+        s.parse()
+    };
+    parsed.map_err(|e| Error::new(ErrorKind::InvalidData, format!("{} : {}", s, e)))
+}
+
+struct EnvCopy {
+    storage: Box<dyn MiscStorage>,
+    offset: u64,
+}
+
+/// The U-Boot environment
+pub struct UBootEnvironment {
+    copies: Vec<EnvCopy>,
+    size: usize,
+    /// Index of the copy the environment was read from
+    active: usize,
+    /// The flags of the active copy, only used with a redundant environment
+    flags: u8,
+    vars: BTreeMap<String, String>,
+}
+
+impl UBootEnvironment {
+    /// Open the environment described by /etc/fw_env.config
+    pub fn open() -> Result<Self, Error> {
+        Self::open_with_config(Path::new(FW_ENV_CONFIG_LOCATION))
+    }
+
+    /// Open the environment described by a fw_env.config file. MTD devices
+    /// are refused, they need an erase before each write.
+    pub fn open_with_config(config: &Path) -> Result<Self, Error> {
+        let locations = parse_fw_env_config(&std::fs::read_to_string(config)?)?;
+        let mut copies = Vec::new();
+        for location in locations.iter() {
+            if is_mtd_device(&location.device) {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    format!(
+                        "U-Boot environment on MTD device {} is not supported",
+                        location.device.display()
+                    ),
+                ));
+            }
+            let storage: Box<dyn MiscStorage> = if location.device.starts_with("/dev/block") {
+                Box::new(BlockDeviceStorage::open(&location.device)?)
+            } else {
+                Box::new(FileStorage::open(&location.device)?)
+            };
+            copies.push((storage, location.offset));
+        }
+        Self::load(copies, locations[0].size)
+    }
+
+    /// Read the environment from one copy, or two copies for a redundant
+    /// environment. Each copy is given as the storage and the offset within it.
+    pub fn load(copies: Vec<(Box<dyn MiscStorage>, u64)>, size: usize) -> Result<Self, Error> {
+        if copies.is_empty() || copies.len() > 2 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Expected one or two environments",
+            ));
+        }
+
+        let mut env = UBootEnvironment {
+            copies: copies
+                .into_iter()
+                .map(|(storage, offset)| EnvCopy { storage, offset })
+                .collect(),
+            size,
+            active: 0,
+            flags: 0,
+            vars: BTreeMap::new(),
+        };
+
+        if env.data_offset() >= size {
+            return Err(Error::new(ErrorKind::InvalidInput, "Environment too small"));
+        }
+
+        // read and check all the copies. A copy is None if the CRC does not match
+        let mut valid = Vec::new();
+        for copy in env.copies.iter() {
+            let mut buffer = vec![0u8; size];
+            copy.storage.read_at(&mut buffer, copy.offset)?;
+            let crc32 = u32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]);
+            let data = &buffer[env.data_offset()..];
+            let algo = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+            if algo.checksum(data) == crc32 {
+                valid.push(Some((buffer[4], parse_env_data(data)?)));
+            } else {
+                valid.push(None);
+            }
+        }
+
+        let active = match valid.as_slice() {
+            [Some(_)] => 0,
+            [Some((flags0, _)), Some((flags1, _))] => {
+                if is_newer(*flags1, *flags0) {
+                    1
+                } else {
+                    0
+                }
+            }
+            [Some(_), None] => 0,
+            [None, Some(_)] => 1,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "No valid U-Boot environment",
+                ))
+            }
+        };
+
+        let (flags, vars) = valid.swap_remove(active).unwrap();
+        env.active = active;
+        env.flags = flags;
+        env.vars = vars;
+        Ok(env)
+    }
+
+    /// Create an empty environment. Nothing is written until save is called.
+    pub fn create(copies: Vec<(Box<dyn MiscStorage>, u64)>, size: usize) -> Result<Self, Error> {
+        if copies.is_empty() || copies.len() > 2 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Expected one or two environments",
+            ));
+        }
+        Ok(UBootEnvironment {
+            copies: copies
+                .into_iter()
+                .map(|(storage, offset)| EnvCopy { storage, offset })
+                .collect(),
+            size,
+            active: 0,
+            flags: 0,
+            vars: BTreeMap::new(),
+        })
+    }
+
+    fn is_redundant(&self) -> bool {
+        self.copies.len() == 2
+    }
+
+    fn data_offset(&self) -> usize {
+        if self.is_redundant() {
+            5
+        } else {
+            4
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).map(|v| v.as_str())
+    }
+
+    /// Set a variable. The change is written out with save.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), Error> {
+        if name.is_empty() || name.contains('=') || name.contains('\0') || value.contains('\0') {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid variable {}={}", name, value),
+            ));
+        }
+        self.vars.insert(String::from(name), String::from(value));
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        self.vars.remove(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.vars.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Write the environment. With a redundant environment, the inactive copy is
+    /// written and becomes the active copy, so the previous copy stays intact
+    /// until the write is complete.
+    pub fn save(&mut self) -> Result<(), Error> {
+        let data_offset = self.data_offset();
+        let mut buffer = vec![0u8; self.size];
+        let mut position = data_offset;
+        for (name, value) in self.vars.iter() {
+            let entry = format!("{}={}\0", name, value);
+            // leave room for the terminating empty string
+            if position + entry.len() >= self.size {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "Variables do not fit into the environment",
+                ));
+            }
+            buffer[position..position + entry.len()].copy_from_slice(entry.as_bytes());
+            position += entry.len();
+        }
+
+        let algo = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+        let crc32 = algo.checksum(&buffer[data_offset..]);
+        buffer[0..4].copy_from_slice(&crc32.to_le_bytes());
+
+        let (target, flags) = if self.is_redundant() {
+            (1 - self.active, self.flags.wrapping_add(1))
+        } else {
+            (0, 0)
+        };
+        if self.is_redundant() {
+            buffer[4] = flags;
+        }
+
+        let copy = &mut self.copies[target];
+        copy.storage.write_verified(&buffer, copy.offset)?;
+        self.active = target;
+        self.flags = flags;
+        Ok(())
+    }
+}
+
+/// Check if the device is an MTD character device. The mtdblock devices are
+/// written through the kernel, which erases the sectors itself.
+fn is_mtd_device(device: &Path) -> bool {
+    match device.file_name().and_then(|name| name.to_str()) {
+        Some(name) => {
+            device.starts_with("/dev") && name.starts_with("mtd") && !name.starts_with("mtdblock")
+        }
+        None => false,
+    }
+}
+
+/// Check if the flags of a redundant copy are newer than the flags of the other
+/// copy, the same way fw_env does it.
+fn is_newer(flags: u8, other: u8) -> bool {
+    if flags == other {
+        false
+    } else if flags == 0 && other == 0xff {
+        true
+    } else if flags == 0xff && other == 0 {
+        false
+    } else {
+        flags > other
+    }
+}
+
+fn parse_env_data(data: &[u8]) -> Result<BTreeMap<String, String>, Error> {
+    let mut vars = BTreeMap::new();
+    for entry in data.split(|b| *b == 0) {
+        if entry.is_empty() {
+            // an empty string terminates the environment
+            break;
+        }
+        let entry =
+            std::str::from_utf8(entry).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        if let Some((name, value)) = entry.split_once('=') {
+            vars.insert(String::from(name), String::from(value));
+        } else {
+            log::warn!("Ignoring invalid U-Boot environment entry : {}", entry);
+        }
+    }
+    Ok(vars)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bootloader::storage::{temp_path, MemoryStorage};
+
+    #[test]
+    fn parse_config() {
+        let config = r###"# Device  offset  Env. size  Flash sector size
+/dev/mmcblk0    0x3fc000    0x4000
+/dev/mmcblk0    0x3f8000    0x4000    0x200
+"###;
+        let locations = parse_fw_env_config(config).unwrap();
+        assert_eq!(locations.len(), 2);
+        assert_eq!(locations[0].device, PathBuf::from("/dev/mmcblk0"));
+        assert_eq!(locations[0].offset, 0x3fc000);
+        assert_eq!(locations[1].offset, 0x3f8000);
+        assert_eq!(locations[1].size, 0x4000);
+        assert_eq!(locations[0].sector_size, None);
+        assert_eq!(locations[1].sector_size, Some(0x200));
+        assert_eq!(locations[1].sector_count, None);
+
+        let locations = parse_fw_env_config("/dev/mtd1 0x0 0x4000 0x10000 2").unwrap();
+        assert_eq!(locations[0].sector_size, Some(0x10000));
+        assert_eq!(locations[0].sector_count, Some(2));
+
+        assert!(parse_fw_env_config("/dev/mmcblk0 0x0").is_err());
+        assert!(parse_fw_env_config("/dev/mtd1 0x0 0x4000 0x10000 2 1").is_err());
+        assert!(parse_fw_env_config("/dev/mtd1 0x0 0x4000 sectors").is_err());
+        assert!(parse_fw_env_config("# nothing").is_err());
+    }
+
+    #[test]
+    fn single_environment() {
+        let env_size = 0x1000;
+        let storage: Box<dyn MiscStorage> = Box::new(MemoryStorage::new(env_size));
+        let mut env = UBootEnvironment::create(vec![(storage, 0)], env_size).unwrap();
+        env.set("BOOT_ORDER", "A B").unwrap();
+        env.set("BOOT_A_LEFT", "3").unwrap();
+        assert!(env.set("INVALID=NAME", "1").is_err());
+        env.save().unwrap();
+
+        let copy = env.copies.remove(0);
+        let mut data = vec![0u8; env_size];
+        copy.storage.read_at(&mut data, 0).unwrap();
+        assert_eq!(&data[4..30], b"BOOT_A_LEFT=3\0BOOT_ORDER=A");
+
+        let env = UBootEnvironment::load(vec![(copy.storage, 0)], env_size).unwrap();
+        assert_eq!(env.get("BOOT_ORDER"), Some("A B"));
+        assert_eq!(env.get("BOOT_A_LEFT"), Some("3"));
+        assert_eq!(env.get("BOOT_B_LEFT"), None);
+    }
+
+    #[test]
+    fn redundant_environment() {
+        let env_size = 0x1000;
+        let copies: Vec<(Box<dyn MiscStorage>, u64)> = vec![
+            (Box::new(MemoryStorage::new(2 * env_size)), 0),
+            (Box::new(MemoryStorage::new(2 * env_size)), env_size as u64),
+        ];
+        let mut env = UBootEnvironment::create(copies, env_size).unwrap();
+        env.set("BOOT_ORDER", "A B").unwrap();
+        env.save().unwrap();
+        assert_eq!(env.active, 1);
+        env.set("BOOT_ORDER", "B A").unwrap();
+        env.save().unwrap();
+        assert_eq!(env.active, 0);
+        assert_eq!(env.flags, 2);
+
+        let copies: Vec<(Box<dyn MiscStorage>, u64)> = env
+            .copies
+            .into_iter()
+            .map(|c| (c.storage, c.offset))
+            .collect();
+        let env = UBootEnvironment::load(copies, env_size).unwrap();
+        assert_eq!(env.active, 0);
+        assert_eq!(env.get("BOOT_ORDER"), Some("B A"));
+    }
+
+    #[test]
+    fn refuse_mtd_devices() {
+        assert!(is_mtd_device(Path::new("/dev/mtd0")));
+        assert!(is_mtd_device(Path::new("/dev/mtd/mtd2")));
+        assert!(!is_mtd_device(Path::new("/dev/mtdblock0")));
+        assert!(!is_mtd_device(Path::new("/dev/mmcblk0")));
+        assert!(!is_mtd_device(Path::new("/boot/uboot.env")));
+
+        let config = temp_path("fw_env.config");
+        std::fs::write(&config, "/dev/mtd0 0x0 0x4000 0x10000\n").unwrap();
+        let result = UBootEnvironment::open_with_config(&config);
+        std::fs::remove_file(&config).unwrap();
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn redundant_flags() {
+        assert!(is_newer(2, 1));
+        assert!(!is_newer(1, 2));
+        assert!(is_newer(0, 0xff));
+        assert!(!is_newer(0xff, 0));
+        assert!(!is_newer(5, 5));
+    }
+}
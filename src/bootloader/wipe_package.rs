@@ -0,0 +1,127 @@
+/*
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+/*
+  The wipe package for A/B devices is staged in the 16K - 64K region of the
+  misc partition. It is stored as a little endian u32 length followed by the
+  package data.
+*/
+
+use std::convert::TryFrom;
+
+use super::error::BootloaderMessageError;
+use super::message::{WIPE_PACKAGE_END_IN_MISC, WIPE_PACKAGE_OFFSET_IN_MISC};
+use super::storage::MiscStorage;
+
+const LENGTH_SIZE: usize = 4;
+
+/// The largest wipe package that fits into the region
+pub const WIPE_PACKAGE_MAX_SIZE: usize =
+    WIPE_PACKAGE_END_IN_MISC - WIPE_PACKAGE_OFFSET_IN_MISC - LENGTH_SIZE;
+
+/// Read the wipe package from the misc storage
+pub fn read_wipe_package(storage: &dyn MiscStorage) -> Result<Vec<u8>, std::io::Error> {
+    let mut length = [0u8; LENGTH_SIZE];
+    storage.read_at(&mut length, WIPE_PACKAGE_OFFSET_IN_MISC as u64)?;
+    let length = usize::try_from(u32::from_le_bytes(length)).unwrap_or(usize::MAX);
+
+    if length > WIPE_PACKAGE_MAX_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            BootloaderMessageError::DataTooLong,
+        ));
+    }
+
+    let mut package = vec![0u8; length];
+    storage.read_at(
+        &mut package,
+        (WIPE_PACKAGE_OFFSET_IN_MISC + LENGTH_SIZE) as u64,
+    )?;
+    Ok(package)
+}
+
+/// Write the wipe package into the misc storage. The package is synced and
+/// verified before the length, so that it survives a power cut once the
+/// length is written.
+pub fn write_wipe_package(
+    storage: &mut dyn MiscStorage,
+    package: &[u8],
+) -> Result<(), std::io::Error> {
+    if package.len() > WIPE_PACKAGE_MAX_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            BootloaderMessageError::DataTooLong,
+        ));
+    }
+
+    // write the data before the length so that an interrupted write
+    // does not leave a length that covers stale data.
+    storage.write_verified(package, (WIPE_PACKAGE_OFFSET_IN_MISC + LENGTH_SIZE) as u64)?;
+    storage.write_verified(
+        &(package.len() as u32).to_le_bytes(),
+        WIPE_PACKAGE_OFFSET_IN_MISC as u64,
+    )
+}
+
+/// Remove the wipe package. Only the length is cleared.
+pub fn clear_wipe_package(storage: &mut dyn MiscStorage) -> Result<(), std::io::Error> {
+    storage.write_verified(&[0u8; LENGTH_SIZE], WIPE_PACKAGE_OFFSET_IN_MISC as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bootloader::storage::MemoryStorage;
+
+    #[test]
+    fn write_and_read_wipe_package() {
+        let mut storage = MemoryStorage::new(WIPE_PACKAGE_END_IN_MISC);
+        assert!(read_wipe_package(&storage).unwrap().is_empty());
+
+        let package: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        write_wipe_package(&mut storage, &package).unwrap();
+        assert_eq!(read_wipe_package(&storage).unwrap(), package);
+        // nothing is written below the wipe package region
+        assert!(storage.as_slice()[0..WIPE_PACKAGE_OFFSET_IN_MISC]
+            .iter()
+            .all(|b| *b == 0));
+
+        let largest = vec![0x5au8; WIPE_PACKAGE_MAX_SIZE];
+        write_wipe_package(&mut storage, &largest).unwrap();
+        assert_eq!(read_wipe_package(&storage).unwrap(), largest);
+
+        clear_wipe_package(&mut storage).unwrap();
+        assert!(read_wipe_package(&storage).unwrap().is_empty());
+    }
+
+    #[test]
+    fn wipe_package_too_long() {
+        let mut storage = MemoryStorage::new(WIPE_PACKAGE_END_IN_MISC);
+        let package = vec![0u8; WIPE_PACKAGE_MAX_SIZE + 1];
+        let error = write_wipe_package(&mut storage, &package).unwrap_err();
+        assert!(matches!(
+            error
+                .get_ref()
+                .unwrap()
+                .downcast_ref::<BootloaderMessageError>(),
+            Some(BootloaderMessageError::DataTooLong)
+        ));
+
+        // an erased flash reads as 0xff
+        storage
+            .write_at(&[0xff; 4], WIPE_PACKAGE_OFFSET_IN_MISC as u64)
+            .unwrap();
+        assert!(read_wipe_package(&storage).is_err());
+    }
+}
@@ -25,6 +25,10 @@ pub enum CoreError {
     DMError,
     #[error("Device Mapper Partition Lookup error")]
     DMPartition,
+    #[error("Rollback index is lower than the stored one")]
+    RollbackIndexTooLow,
+    #[error("Rollback index store is corrupted")]
+    RollbackIndexCorrupted,
     #[error("unknown error")]
     Unknown,
 }
@@ -13,48 +13,507 @@
 */
 
 use libc::c_ulong;
-use log::{debug, trace};
+use log::{debug, trace, warn};
 use std::{
-    ffi::{CString},
+    ffi::{CStr, CString},
+    fmt::Display,
     io::Error,
+    path::PathBuf,
+    time::Duration,
 };
+use thiserror::Error;
 
 use std::str::FromStr;
 
+/// Error for a fs_mgr flag with a missing or malformed value
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum FsManagerFlagError {
+    #[error("{0} needs a value")]
+    MissingValue(String),
+    #[error("{0} does not take a value")]
+    UnexpectedValue(String),
+    #[error("Invalid value for {flag} : {value}")]
+    InvalidValue { flag: String, value: String },
+}
+
+/// Why a line of the fstab was rejected
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum FstabErrorReason {
+    #[error("expected 5 fields, found {0}")]
+    WrongFieldCount(usize),
+    #[error("unknown mount option")]
+    UnknownMountOption,
+    #[error("unknown fs_mgr flag")]
+    UnknownFlag,
+    #[error("{0}")]
+    InvalidFlag(FsManagerFlagError),
+    #[error("invalid path")]
+    InvalidPath,
+    #[error("invalid filesystem type")]
+    InvalidFsType,
+    #[error("NUL byte")]
+    NulByte,
+    #[error("slotselect cannot be used with UUID= or PARTUUID=")]
+    SlotSelectWithUuid,
+}
+
+/// Why an entry cannot be written to an fstab without losing information
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum FstabWriteError {
+    #[error("MS_* flags without an option name : {0:#x}")]
+    UnnamedMountFlags(c_ulong),
+    #[error("the {0} are not valid UTF-8")]
+    NotUtf8(&'static str),
+}
+
+/// Error for a line of the fstab. Lines and columns start at 1.
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("fstab line {line}, column {column}: {reason} : {token:?}")]
+pub struct FstabError {
+    pub line: usize,
+    pub column: usize,
+    pub reason: FstabErrorReason,
+    /// The offending part of the line
+    pub token: String,
+}
+
+/// How strictly the fstab is parsed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParseMode {
+    /// Any problem is an error
+    Strict,
+    /// Lines that cannot be used are skipped. Unknown mount options and flags
+    /// are kept. Both are reported as warnings.
+    Lenient,
+}
+
+/// Mount options with a MS_* flag. "rw" clears MS_RDONLY and is not listed.
+const MOUNT_OPTIONS: &[(&str, c_ulong)] = &[
+    ("ro", libc::MS_RDONLY),
+    ("dirsync", libc::MS_DIRSYNC),
+    ("lazytime", libc::MS_LAZYTIME),
+    ("mandlock", libc::MS_MANDLOCK),
+    ("noatime", libc::MS_NOATIME),
+    ("nodev", libc::MS_NODEV),
+    ("nodiratime", libc::MS_NODIRATIME),
+    ("noexec", libc::MS_NOEXEC),
+    ("nosuid", libc::MS_NOSUID),
+    ("silent", libc::MS_SILENT),
+    ("strictatime", libc::MS_STRICTATIME),
+    ("sync", libc::MS_SYNCHRONOUS),
+];
+
+/// Filesystem specific options known to the filesystems we mount, without
+/// their value. Options that are not known are rejected in strict mode.
+const KNOWN_DATA_OPTIONS: &[&str] = &[
+    // generic and SELinux
+    "acl",
+    "noacl",
+    "user_xattr",
+    "nouser_xattr",
+    "context",
+    "fscontext",
+    "defcontext",
+    "rootcontext",
+    "seclabel",
+    "discard",
+    "nodiscard",
+    "errors",
+    "quota",
+    "usrquota",
+    "grpquota",
+    "prjquota",
+    "resuid",
+    "resgid",
+    "uid",
+    "gid",
+    "mode",
+    // ext4
+    "data",
+    "barrier",
+    "nobarrier",
+    "commit",
+    "journal_checksum",
+    "journal_async_commit",
+    "delalloc",
+    "nodelalloc",
+    "noauto_da_alloc",
+    "auto_da_alloc",
+    "init_itable",
+    "noinit_itable",
+    "inode_readahead_blks",
+    "stripe",
+    "max_batch_time",
+    "min_batch_time",
+    "dax",
+    "nombcache",
+    "noload",
+    "journal_ioprio",
+    "grpid",
+    "nogrpid",
+    // f2fs
+    "background_gc",
+    "checkpoint",
+    "checkpoint_merge",
+    "fsync_mode",
+    "inline_xattr",
+    "noinline_xattr",
+    "inline_data",
+    "noinline_data",
+    "inline_dentry",
+    "noinline_dentry",
+    "flush_merge",
+    "extent_cache",
+    "noextent_cache",
+    "active_logs",
+    "alloc_mode",
+    "reserve_root",
+    "compress_algorithm",
+    "compress_extension",
+    "inlinecrypt",
+    "age_extent_cache",
+    "discard_unit",
+    "compress_mode",
+    "compress_cache",
+    "atgc",
+    "gc_merge",
+    "nogc_merge",
+    // tmpfs
+    "size",
+    "nr_blocks",
+    "nr_inodes",
+    "huge",
+    // vfat
+    "fmask",
+    "dmask",
+    "umask",
+    "utf8",
+    "iocharset",
+    "codepage",
+    "shortname",
+    "flush",
+];
+
+/// fs_mgr flags from Android fstabs that need no special handling here.
+/// Flags that are not known are rejected in strict mode.
+const KNOWN_OTHER_FLAGS: &[&str] = &[
+    "wait",
+    "check",
+    "nonremovable",
+    "recoveryonly",
+    "noemulatedsd",
+    "notrim",
+    "formattable",
+    "resize",
+    "quota",
+    "latemount",
+    "nofail",
+    "fsverity",
+    "checkpoint",
+    "encryptable",
+    "forceencrypt",
+    "forcefdeorfbe",
+    "keydirectory",
+    "metadata_encryption",
+    "sysfs_path",
+    "zram_backingdev_size",
+    "max_comp_streams",
+    "swapprio",
+    "eraseblk",
+    "logicalblk",
+    "length",
+    "voldmanaged",
+    "readahead_size_kb",
+    "avb_keys",
+    "slotselect_other",
+    "wrappedkey",
+    "fscompress",
+];
+
+/// Size of the zram device
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ZramSize {
+    /// Percentage of the total memory
+    Percent(u8),
+    /// Size in bytes
+    Bytes(u64),
+}
+
+/// Encryption modes from fileencryption=contents[:filenames[:options]].
+/// An empty mode selects the default of vold.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileEncryption {
+    /// Encryption of the file contents, for example "aes-256-xts"
+    pub contents_mode: String,
+    /// Encryption of the file names, for example "aes-256-cts"
+    pub filenames_mode: Option<String>,
+    /// Policy options, for example "v2+inlinecrypt_optimized"
+    pub options: Option<String>,
+}
+
 /// FsManagerFlags
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum FsManagerFlags {
     /// Mount this partition during early boot
     FirstStageMount,
     /// Use slot select mechanism to decide which partition to
     /// mount. The bootmanager HAL is used to get details about
-    /// the slot.
+    /// the slot. The slot suffix is appended to the device path,
+    /// LABEL= or PARTLABEL=, for example LABEL=system becomes
+    /// LABEL=system_a. UUID= and PARTUUID= are the same in every
+    /// slot and cannot be used with it.
     SlotSelect,
     /// This is a logical partition (using DM Mapper. Not supported yet)
     Logical,
     /// This fs is protected with metadata in the verity partition.
     Verity,
+    /// Verify the partition with AVB. avb=<partition> names the vbmeta
+    /// partition that holds the descriptor, for example vbmeta_system.
+    Avb(Option<String>),
+    /// File based encryption
+    FileEncryption(FileEncryption),
+    /// Use a zram device of this size for swap
+    ZramSize(ZramSize),
+    /// Space in bytes reserved for privileged processes
+    ReservedSize(u64),
+    /// Maximum time to run fsck before the partition is mounted
+    FsckTimeout(Duration),
+    /// Key used to verify the verity metadata
+    VerityKey(PathBuf),
+    /// Maximum time to wait for the device to appear
+    WaitTimeout(Duration),
     /// Other flags
     Other(String),
 }
 
 impl FromStr for FsManagerFlags {
-    type Err = std::io::Error;
+    type Err = FsManagerFlagError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "slotselect" => Ok(FsManagerFlags::SlotSelect),
-            "first_stage_mount" => Ok(FsManagerFlags::FirstStageMount),
-            "verity" => Ok(FsManagerFlags::Verity),
-            "logical" => Ok(FsManagerFlags::Logical),
+        let (flag, value) = match s.split_once('=') {
+            Some((flag, value)) => (flag, Some(value)),
+            None => (s, None),
+        };
+        let invalid_value = || FsManagerFlagError::InvalidValue {
+            flag: String::from(flag),
+            value: String::from(value.unwrap_or_default()),
+        };
+        let required_value = || match value {
+            Some(value) if !value.is_empty() => Ok(value),
+            _ => Err(FsManagerFlagError::MissingValue(String::from(flag))),
+        };
+
+        let bare_flag = match flag {
+            "slotselect" => Some(FsManagerFlags::SlotSelect),
+            "first_stage_mount" => Some(FsManagerFlags::FirstStageMount),
+            "verity" => Some(FsManagerFlags::Verity),
+            "logical" => Some(FsManagerFlags::Logical),
+            _ => None,
+        };
+        if let Some(bare_flag) = bare_flag {
+            return match value {
+                Some(_) => Err(FsManagerFlagError::UnexpectedValue(String::from(flag))),
+                None => Ok(bare_flag),
+            };
+        }
+
+        match flag {
+            "avb" => match value {
+                None => Ok(FsManagerFlags::Avb(None)),
+                Some(partition) if !partition.is_empty() && !partition.contains('/') => {
+                    Ok(FsManagerFlags::Avb(Some(String::from(partition))))
+                }
+                Some(_) => Err(invalid_value()),
+            },
+            "fileencryption" => {
+                let mut modes = required_value()?.splitn(3, ':').map(String::from);
+                Ok(FsManagerFlags::FileEncryption(FileEncryption {
+                    contents_mode: modes.next().unwrap_or_default(),
+                    filenames_mode: modes.next(),
+                    options: modes.next(),
+                }))
+            }
+            "zramsize" => {
+                let value = required_value()?;
+                let size = match value.strip_suffix('%') {
+                    Some(percent) => match percent.parse::<u8>() {
+                        Ok(percent) if percent <= 100 => ZramSize::Percent(percent),
+                        _ => return Err(invalid_value()),
+                    },
+                    None => ZramSize::Bytes(parse_size(value).ok_or_else(invalid_value)?),
+                };
+                Ok(FsManagerFlags::ZramSize(size))
+            }
+            "reservedsize" => Ok(FsManagerFlags::ReservedSize(
+                parse_size(required_value()?).ok_or_else(invalid_value)?,
+            )),
+            "fsck_timeout" => Ok(FsManagerFlags::FsckTimeout(
+                parse_timeout(required_value()?).ok_or_else(invalid_value)?,
+            )),
+            "verity_key" => {
+                let path = PathBuf::from(required_value()?);
+                if path.is_absolute() {
+                    Ok(FsManagerFlags::VerityKey(path))
+                } else {
+                    Err(invalid_value())
+                }
+            }
+            "wait_timeout" => Ok(FsManagerFlags::WaitTimeout(
+                parse_timeout(required_value()?).ok_or_else(invalid_value)?,
+            )),
             _ => Ok(FsManagerFlags::Other(String::from(s))),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+/// Parse a size in bytes with an optional K, M or G suffix, as fs_mgr does
+fn parse_size(value: &str) -> Option<u64> {
+    let (number, multiplier) = match value.char_indices().last()? {
+        (index, 'k') | (index, 'K') => (&value[..index], 1u64 << 10),
+        (index, 'm') | (index, 'M') => (&value[..index], 1u64 << 20),
+        (index, 'g') | (index, 'G') => (&value[..index], 1u64 << 30),
+        _ => (value, 1),
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+/// Parse a timeout in seconds, or in milliseconds with the "ms" suffix
+fn parse_timeout(value: &str) -> Option<Duration> {
+    match value.strip_suffix("ms") {
+        Some(millis) => millis.parse().ok().map(Duration::from_millis),
+        None => value
+            .strip_suffix('s')
+            .unwrap_or(value)
+            .parse()
+            .ok()
+            .map(Duration::from_secs),
+    }
+}
+
+/// Format a size in bytes with the largest K, M or G suffix that keeps it exact
+fn format_size(size: u64) -> String {
+    match size {
+        0 => String::from("0"),
+        s if s % (1 << 30) == 0 => format!("{}G", s >> 30),
+        s if s % (1 << 20) == 0 => format!("{}M", s >> 20),
+        s if s % (1 << 10) == 0 => format!("{}K", s >> 10),
+        s => s.to_string(),
+    }
+}
+
+/// Format a timeout in seconds, or in milliseconds if it is not whole seconds
+fn format_timeout(timeout: &Duration) -> String {
+    if timeout.subsec_nanos() == 0 {
+        timeout.as_secs().to_string()
+    } else {
+        format!("{}ms", timeout.as_millis())
+    }
+}
+
+impl Display for FsManagerFlags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FsManagerFlags::FirstStageMount => write!(f, "first_stage_mount"),
+            FsManagerFlags::SlotSelect => write!(f, "slotselect"),
+            FsManagerFlags::Logical => write!(f, "logical"),
+            FsManagerFlags::Verity => write!(f, "verity"),
+            FsManagerFlags::Avb(None) => write!(f, "avb"),
+            FsManagerFlags::Avb(Some(partition)) => write!(f, "avb={}", partition),
+            FsManagerFlags::FileEncryption(encryption) => {
+                write!(f, "fileencryption={}", encryption.contents_mode)?;
+                for mode in [&encryption.filenames_mode, &encryption.options]
+                    .iter()
+                    .filter_map(|m| m.as_ref())
+                {
+                    write!(f, ":{}", mode)?;
+                }
+                Ok(())
+            }
+            FsManagerFlags::ZramSize(ZramSize::Percent(percent)) => {
+                write!(f, "zramsize={}%", percent)
+            }
+            FsManagerFlags::ZramSize(ZramSize::Bytes(size)) => {
+                write!(f, "zramsize={}", format_size(*size))
+            }
+            FsManagerFlags::ReservedSize(size) => write!(f, "reservedsize={}", format_size(*size)),
+            FsManagerFlags::FsckTimeout(timeout) => {
+                write!(f, "fsck_timeout={}", format_timeout(timeout))
+            }
+            FsManagerFlags::VerityKey(path) => write!(f, "verity_key={}", path.display()),
+            FsManagerFlags::WaitTimeout(timeout) => {
+                write!(f, "wait_timeout={}", format_timeout(timeout))
+            }
+            FsManagerFlags::Other(flag) => write!(f, "{}", flag),
+        }
+    }
+}
+
+/// How the device of an fstab entry is named
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceSpec {
+    /// Path of the device entry, for example /dev/block/by-name/system
+    Path(PathBuf),
+    /// LABEL=<label of the filesystem>
+    Label(String),
+    /// UUID=<UUID of the filesystem>
+    Uuid(String),
+    /// PARTLABEL=<name of the GPT partition>
+    PartLabel(String),
+    /// PARTUUID=<unique GUID of the GPT partition>
+    PartUuid(String),
+    /// No device is needed, for example for tmpfs
+    Pseudo(String),
+}
+
+impl FromStr for DeviceSpec {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tagged = |tag: &str, value: &str| {
+            if value.is_empty() {
+                Err(Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("{} needs a value", tag),
+                ))
+            } else {
+                Ok(String::from(value))
+            }
+        };
+
+        match s.split_once('=') {
+            Some(("LABEL", value)) => Ok(DeviceSpec::Label(tagged("LABEL", value)?)),
+            Some(("UUID", value)) => Ok(DeviceSpec::Uuid(tagged("UUID", value)?)),
+            Some(("PARTLABEL", value)) => Ok(DeviceSpec::PartLabel(tagged("PARTLABEL", value)?)),
+            Some(("PARTUUID", value)) => Ok(DeviceSpec::PartUuid(tagged("PARTUUID", value)?)),
+            _ if s.starts_with('/') => Ok(DeviceSpec::Path(PathBuf::from(s))),
+            _ if !s.is_empty() && !s.contains(&['/', '='][..]) => {
+                Ok(DeviceSpec::Pseudo(String::from(s)))
+            }
+            _ => Err(Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid device : {}", s),
+            )),
+        }
+    }
+}
+
+impl Display for DeviceSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceSpec::Path(path) => write!(f, "{}", path.display()),
+            DeviceSpec::Label(label) => write!(f, "LABEL={}", label),
+            DeviceSpec::Uuid(uuid) => write!(f, "UUID={}", uuid),
+            DeviceSpec::PartLabel(label) => write!(f, "PARTLABEL={}", label),
+            DeviceSpec::PartUuid(uuid) => write!(f, "PARTUUID={}", uuid),
+            DeviceSpec::Pseudo(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct FsEntry {
-    /// The device identifier
+    /// The device identifier. A path, LABEL=, UUID=, PARTLABEL= or PARTUUID=
+    /// (see DeviceSpec)
     pub fs_spec: CString,
     /// The mount point
     pub mountpoint: CString,
@@ -62,6 +521,9 @@ pub struct FsEntry {
     pub vfs_type: CString,
     /// Mount options to use. Directly store in the flags format
     pub mount_options: libc::c_ulong,
+    /// Filesystem specific options that have no MS_* flag, for example
+    /// "errors=remount-ro,data=ordered". Passed as the data argument of mount(2).
+    pub data_options: CString,
     /// Filessytem manager flags for special handling of each
     /// mount. For example, if a partition is affected
     /// by the dual partition scheme, then the slotselect flag must be set.
@@ -69,72 +531,276 @@ pub struct FsEntry {
 }
 
 impl FsEntry {
+    /// Parse the fstab leniently, the lines that cannot be used are skipped
+    /// and the problems are logged. Use parse_strict to refuse them.
     pub fn parse_entries(contents: &str, slot_suffix: &str) -> Result<Vec<FsEntry>, Error> {
+        let (entries, warnings) = Self::parse_lenient(contents, slot_suffix);
+        for warning in warnings {
+            warn!("{}", warning);
+        }
+        Ok(entries)
+    }
+
+    /// Parse the fstab. The first problem found is returned as an error.
+    pub fn parse_strict(contents: &str, slot_suffix: &str) -> Result<Vec<FsEntry>, FstabError> {
         let mut entries: Vec<FsEntry> = Vec::new();
-        //let mut contents = String::new();
-        //file.read_to_string(&mut contents)?;
+        let mut warnings = Vec::new();
+        for (index, line) in contents.lines().enumerate() {
+            if let Some(entry) = Self::parse_line(
+                line,
+                index + 1,
+                slot_suffix,
+                ParseMode::Strict,
+                &mut warnings,
+            )? {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
 
-        for line in contents.lines() {
-            if line.starts_with('#') {
-                trace!("Skipping commented line: {}", line);
-                continue;
+    /// Parse the fstab, skipping the lines that cannot be used. The problems
+    /// are returned as warnings along with the entries.
+    pub fn parse_lenient(contents: &str, slot_suffix: &str) -> (Vec<FsEntry>, Vec<FstabError>) {
+        let mut entries: Vec<FsEntry> = Vec::new();
+        let mut warnings = Vec::new();
+        for (index, line) in contents.lines().enumerate() {
+            match Self::parse_line(
+                line,
+                index + 1,
+                slot_suffix,
+                ParseMode::Lenient,
+                &mut warnings,
+            ) {
+                Ok(Some(entry)) => entries.push(entry),
+                Ok(None) => {}
+                Err(e) => {
+                    debug!("Skipping fstab entry: {}", e);
+                    warnings.push(e);
+                }
             }
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() != 5 {
-                trace!("Unknown fstab entry: {}", line);
-                continue;
+        }
+        (entries, warnings)
+    }
+
+    /// Parse one line. Returns None for comments and empty lines. Problems that
+    /// do not prevent using the line are errors in strict mode and are added to
+    /// the warnings in lenient mode.
+    fn parse_line(
+        line: &str,
+        line_number: usize,
+        slot_suffix: &str,
+        mode: ParseMode,
+        warnings: &mut Vec<FstabError>,
+    ) -> Result<Option<FsEntry>, FstabError> {
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            trace!("Skipping commented line: {}", line);
+            return Ok(None);
+        }
+
+        let error = |column: usize, reason: FstabErrorReason, token: &str| FstabError {
+            line: line_number,
+            column: column + 1,
+            reason,
+            token: String::from(token),
+        };
+        let mut report = |e: FstabError| match mode {
+            ParseMode::Strict => Err(e),
+            ParseMode::Lenient => {
+                warnings.push(e);
+                Ok(())
             }
+        };
 
-            let flags: Vec<FsManagerFlags> = parts[4]
-                .split(',')
-                .map(|s| FsManagerFlags::from_str(s).unwrap())
-                .collect();
+        let parts: Vec<(usize, &str)> = fields_with_columns(line);
+        if parts.len() != 5 {
+            return Err(error(
+                0,
+                FstabErrorReason::WrongFieldCount(parts.len()),
+                line,
+            ));
+        }
+        if let Some(&(column, field)) = parts.iter().find(|(_, field)| field.contains('\0')) {
+            let nul_position = field.find('\0').unwrap_or_default();
+            return Err(error(
+                column + nul_position,
+                FstabErrorReason::NulByte,
+                field,
+            ));
+        }
+        let (spec_column, spec) = parts[0];
+        let (mountpoint_column, mountpoint) = parts[1];
+        let (vfs_type_column, vfs_type) = parts[2];
 
-            let fs_spec = if flags
-                .iter()
-                .find(|f| matches!(f, FsManagerFlags::SlotSelect))
-                .is_some()
-            {
-                let full_spec = format!("{}_{}", parts[0], slot_suffix);
-                CString::new(full_spec).unwrap()
-            } else {
-                CString::new(parts[0]).unwrap()
-            };
+        let mut flags: Vec<FsManagerFlags> = Vec::new();
+        for (column, flag) in split_with_columns(parts[4]) {
+            if flag == "defaults" {
+                continue;
+            }
+            let parsed = flag
+                .parse::<FsManagerFlags>()
+                .map_err(|e| error(column, FstabErrorReason::InvalidFlag(e), flag))?;
+            if let FsManagerFlags::Other(other) = &parsed {
+                let name = other.split('=').next().unwrap_or_default();
+                if !KNOWN_OTHER_FLAGS.contains(&name) {
+                    report(error(column, FstabErrorReason::UnknownFlag, flag))?;
+                }
+            }
+            flags.push(parsed);
+        }
 
-            let mut mount_options: libc::c_ulong = 0;
-            for p in parts[3].split(",") {
-                mount_options |= Self::get_mount_option(p);
+        let slot_select = flags
+            .iter()
+            .any(|f| matches!(f, FsManagerFlags::SlotSelect));
+        if slot_select {
+            if let Ok(DeviceSpec::Uuid(_)) | Ok(DeviceSpec::PartUuid(_)) = spec.parse() {
+                return Err(error(
+                    spec_column,
+                    FstabErrorReason::SlotSelectWithUuid,
+                    spec,
+                ));
             }
+        }
+        let spec = if slot_select {
+            format!("{}_{}", spec, slot_suffix)
+        } else {
+            String::from(spec)
+        };
+        spec.parse::<DeviceSpec>()
+            .map_err(|_e| error(spec_column, FstabErrorReason::InvalidPath, &spec))?;
+        if !mountpoint.starts_with('/') && !["none", "swap", "auto"].contains(&mountpoint) {
+            return Err(error(
+                mountpoint_column,
+                FstabErrorReason::InvalidPath,
+                mountpoint,
+            ));
+        }
+        if !vfs_type
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+        {
+            return Err(error(
+                vfs_type_column,
+                FstabErrorReason::InvalidFsType,
+                vfs_type,
+            ));
+        }
 
-            let entry = FsEntry {
-                fs_spec,
-                mountpoint: CString::new(parts[1]).unwrap(),
-                vfs_type: CString::new(parts[2]).unwrap(),
-                mount_options,
-                fs_manager_flags: flags,
-            };
-            entries.push(entry)
+        let mut mount_options: libc::c_ulong = 0;
+        let mut data_options: Vec<&str> = Vec::new();
+        for (column, p) in split_with_columns(parts[3]) {
+            match Self::get_mount_option(p) {
+                Some(flag) => mount_options |= flag,
+                None if Self::is_userspace_option(p) => {
+                    trace!("Ignoring userspace mount option: {}", p)
+                }
+                None => {
+                    let name = p.split('=').next().unwrap_or_default();
+                    if !KNOWN_DATA_OPTIONS.contains(&name) {
+                        report(error(column, FstabErrorReason::UnknownMountOption, p))?;
+                    }
+                    data_options.push(p)
+                }
+            }
         }
-        Ok(entries)
+
+        let c_string = |column: usize, value: &str| {
+            CString::new(value).map_err(|_e| error(column, FstabErrorReason::NulByte, value))
+        };
+        Ok(Some(FsEntry {
+            fs_spec: c_string(spec_column, &spec)?,
+            mountpoint: c_string(mountpoint_column, mountpoint)?,
+            vfs_type: c_string(vfs_type_column, vfs_type)?,
+            mount_options,
+            data_options: c_string(parts[3].0, &data_options.join(","))?,
+            fs_manager_flags: flags,
+        }))
     }
 
-    fn get_mount_option(option: &str) -> libc::c_ulong {
+    /// The MS_* flag for a mount option. None if the option has no flag.
+    fn get_mount_option(option: &str) -> Option<libc::c_ulong> {
         match option {
-            "ro" => libc::MS_RDONLY,
-            "rw" => 0, // default is read/write so nothing to do here
-            "dirsync" => libc::MS_DIRSYNC,
-            "lazytime" => libc::MS_LAZYTIME,
-            "mandlock" => libc::MS_MANDLOCK,
-            "noatime" => libc::MS_NOATIME,
-            "nodev" => libc::MS_NODEV,
-            "nodiratime" => libc::MS_NODIRATIME,
-            "noexec" => libc::MS_NOEXEC,
-            "nosiud" => libc::MS_NOSUID,
-            "silent" => libc::MS_SILENT,
-            "strictatime" => libc::MS_STRICTATIME,
-            "sync" => libc::MS_SYNC as c_ulong,
-            _ => 0,
+            "rw" => Some(0), // default is read/write so nothing to do here
+            _ => MOUNT_OPTIONS
+                .iter()
+                .find(|(name, _)| *name == option)
+                .map(|(_, flag)| *flag),
+        }
+    }
+
+    /// The mount options as written in the fstab. MS_* flags that have no
+    /// option name cannot be written.
+    fn mount_options_string(&self) -> Result<String, FstabWriteError> {
+        let named = MOUNT_OPTIONS
+            .iter()
+            .fold(0, |named, (_, flag)| named | flag);
+        let unnamed = self.mount_options & !named;
+        if unnamed != 0 {
+            return Err(FstabWriteError::UnnamedMountFlags(unnamed));
+        }
+
+        let mut options = vec![if self.mount_options & libc::MS_RDONLY != 0 {
+            "ro"
+        } else {
+            "rw"
+        }];
+        options.extend(
+            MOUNT_OPTIONS
+                .iter()
+                .filter(|(_, flag)| *flag != libc::MS_RDONLY && self.mount_options & flag != 0)
+                .map(|(name, _)| *name),
+        );
+        if !self.data_options.as_bytes().is_empty() {
+            options.push(utf8(&self.data_options, "data options")?);
         }
+        Ok(options.join(","))
+    }
+
+    /// Write the entry as a line of the fstab, with the given device
+    fn write_line(&self, fs_spec: &str) -> Result<String, FstabWriteError> {
+        let flags: Vec<String> = self
+            .fs_manager_flags
+            .iter()
+            .map(|f| f.to_string())
+            .collect();
+        Ok(format!(
+            "{} {} {} {} {}",
+            fs_spec,
+            utf8(&self.mountpoint, "mount point")?,
+            utf8(&self.vfs_type, "filesystem type")?,
+            self.mount_options_string()?,
+            if flags.is_empty() {
+                String::from("defaults")
+            } else {
+                flags.join(",")
+            }
+        ))
+    }
+
+    /// The entry as a line of the fstab, without a line feed. The device keeps
+    /// the slot suffix. Fails if the entry cannot be written without losing
+    /// information. This is not Display, which cannot fail and would have to
+    /// drop what cannot be written.
+    pub fn to_line(&self) -> Result<String, FstabWriteError> {
+        self.write_line(utf8(&self.fs_spec, "device")?)
+    }
+
+    /// Options that are only meaningful to mount(8) and must not be passed
+    /// to the kernel
+    fn is_userspace_option(option: &str) -> bool {
+        matches!(
+            option,
+            "defaults" | "auto" | "noauto" | "user" | "nouser" | "users" | "nofail" | "_netdev"
+        ) || option.starts_with("x-")
+    }
+
+    /// How the device of the entry is named
+    pub fn device_spec(&self) -> Result<DeviceSpec, Error> {
+        self.fs_spec
+            .to_str()
+            .map_err(|e| Error::new(std::io::ErrorKind::InvalidInput, e))?
+            .parse()
     }
 
     pub fn is_first_stage_mount(&self) -> bool {
@@ -174,6 +840,136 @@ impl FsEntry {
     }
 }
 
+/// A field of the entry as text, for writing it to the fstab
+fn utf8<'a>(field: &'a CStr, name: &'static str) -> Result<&'a str, FstabWriteError> {
+    field.to_str().map_err(|_e| FstabWriteError::NotUtf8(name))
+}
+
+/// A line of the fstab
+#[derive(Debug, Clone, PartialEq)]
+pub enum FstabLine {
+    /// A comment or an empty line, kept as it is
+    Comment(String),
+    Entry(FsEntry),
+}
+
+/// An fstab with its comments, so that it can be changed and written back.
+/// Serializing the fstab and parsing it again gives the same fstab.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fstab {
+    pub lines: Vec<FstabLine>,
+    /// The suffix added to the devices of slotselect entries
+    slot_suffix: String,
+}
+
+impl Fstab {
+    /// Parse the fstab strictly, keeping the comments
+    pub fn parse(contents: &str, slot_suffix: &str) -> Result<Self, FstabError> {
+        let mut lines = Vec::new();
+        let mut warnings = Vec::new();
+        for (index, line) in contents.lines().enumerate() {
+            match FsEntry::parse_line(
+                line,
+                index + 1,
+                slot_suffix,
+                ParseMode::Strict,
+                &mut warnings,
+            )? {
+                Some(entry) => lines.push(FstabLine::Entry(entry)),
+                None => lines.push(FstabLine::Comment(String::from(line))),
+            }
+        }
+        Ok(Fstab {
+            lines,
+            slot_suffix: String::from(slot_suffix),
+        })
+    }
+
+    pub fn slot_suffix(&self) -> &str {
+        &self.slot_suffix
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &FsEntry> {
+        self.lines.iter().filter_map(|line| match line {
+            FstabLine::Entry(entry) => Some(entry),
+            FstabLine::Comment(_) => None,
+        })
+    }
+
+    pub fn entries_mut(&mut self) -> impl Iterator<Item = &mut FsEntry> {
+        self.lines.iter_mut().filter_map(|line| match line {
+            FstabLine::Entry(entry) => Some(entry),
+            FstabLine::Comment(_) => None,
+        })
+    }
+
+    pub fn into_entries(self) -> Vec<FsEntry> {
+        self.lines
+            .into_iter()
+            .filter_map(|line| match line {
+                FstabLine::Entry(entry) => Some(entry),
+                FstabLine::Comment(_) => None,
+            })
+            .collect()
+    }
+
+    /// Write the fstab, with the comments. The slot suffix is removed from the
+    /// devices of slotselect entries as parsing adds it again. Fails if an
+    /// entry cannot be written without losing information.
+    pub fn serialize(&self) -> Result<String, FstabWriteError> {
+        let suffix = format!("_{}", self.slot_suffix);
+        let mut contents = String::new();
+        for line in self.lines.iter() {
+            match line {
+                FstabLine::Comment(comment) => contents.push_str(comment),
+                FstabLine::Entry(entry) => {
+                    let fs_spec = utf8(&entry.fs_spec, "device")?;
+                    let fs_spec = match fs_spec.strip_suffix(suffix.as_str()) {
+                        Some(spec) if entry.is_slot_selected() => spec,
+                        _ => fs_spec,
+                    };
+                    contents.push_str(&entry.write_line(fs_spec)?);
+                }
+            }
+            contents.push('\n');
+        }
+        Ok(contents)
+    }
+}
+
+/// Split a line into whitespace separated fields, with the byte offset of each field
+fn fields_with_columns(line: &str) -> Vec<(usize, &str)> {
+    let mut fields = Vec::new();
+    let mut start = None;
+    for (index, c) in line.char_indices() {
+        match (c.is_whitespace(), start) {
+            (true, Some(s)) => {
+                fields.push((s, &line[s..index]));
+                start = None;
+            }
+            (false, None) => start = Some(index),
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        fields.push((s, &line[s..]));
+    }
+    fields
+}
+
+/// Split a comma separated field, with the byte offset of each part in the line
+fn split_with_columns((column, field): (usize, &str)) -> Vec<(usize, &str)> {
+    let mut offset = column;
+    field
+        .split(',')
+        .map(|part| {
+            let item = (offset, part);
+            offset += part.len() + 1;
+            item
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -190,4 +986,382 @@ mod test {
 
         let _entries = FsEntry::parse_entries(fstab, "a");
     }
+
+    #[test]
+    fn data_options() {
+        let fstab = r###"
+/dev/block/by-name/data   /data   ext4    noatime,nosuid,nodev,discard,errors=remount-ro,data=ordered    first_stage_mount
+tmpfs                     /tmp    tmpfs   defaults,noauto,size=64m,mode=1777    first_stage_mount
+/dev/block/by-name/system /       ext4    ro    first_stage_mount
+"###;
+
+        let entries = FsEntry::parse_entries(fstab, "a").unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(
+            entries[0].mount_options,
+            libc::MS_NOATIME | libc::MS_NOSUID | libc::MS_NODEV
+        );
+        assert_eq!(
+            entries[0].data_options.to_str().unwrap(),
+            "discard,errors=remount-ro,data=ordered"
+        );
+        assert_eq!(entries[1].mount_options, 0);
+        assert_eq!(
+            entries[1].data_options.to_str().unwrap(),
+            "size=64m,mode=1777"
+        );
+        assert_eq!(entries[2].mount_options, libc::MS_RDONLY);
+        assert!(entries[2].data_options.as_bytes().is_empty());
+        assert_eq!(
+            FsEntry::get_mount_option("sync"),
+            Some(libc::MS_SYNCHRONOUS)
+        );
+    }
+
+    #[test]
+    fn valued_flags() {
+        let flags: Vec<FsManagerFlags> = "avb=vbmeta_system,avb,fileencryption=aes-256-xts:aes-256-cts:v2,zramsize=50%,zramsize=512M,reservedsize=128M,fsck_timeout=30,verity_key=/etc/veritykey.pub,wait_timeout=500ms,quota"
+            .split(',')
+            .map(|f| f.parse().unwrap())
+            .collect();
+        assert_eq!(
+            flags,
+            vec![
+                FsManagerFlags::Avb(Some(String::from("vbmeta_system"))),
+                FsManagerFlags::Avb(None),
+                FsManagerFlags::FileEncryption(FileEncryption {
+                    contents_mode: String::from("aes-256-xts"),
+                    filenames_mode: Some(String::from("aes-256-cts")),
+                    options: Some(String::from("v2")),
+                }),
+                FsManagerFlags::ZramSize(ZramSize::Percent(50)),
+                FsManagerFlags::ZramSize(ZramSize::Bytes(512 << 20)),
+                FsManagerFlags::ReservedSize(128 << 20),
+                FsManagerFlags::FsckTimeout(Duration::from_secs(30)),
+                FsManagerFlags::VerityKey(PathBuf::from("/etc/veritykey.pub")),
+                FsManagerFlags::WaitTimeout(Duration::from_millis(500)),
+                FsManagerFlags::Other(String::from("quota")),
+            ]
+        );
+
+        for malformed in [
+            "zramsize=150%",
+            "zramsize=lots",
+            "reservedsize=12X",
+            "fsck_timeout=soon",
+            "verity_key=veritykey.pub",
+            "avb=",
+        ]
+        .iter()
+        {
+            assert!(
+                matches!(
+                    malformed.parse::<FsManagerFlags>(),
+                    Err(FsManagerFlagError::InvalidValue { .. })
+                ),
+                "{}",
+                malformed
+            );
+        }
+        assert_eq!(
+            "wait_timeout=".parse::<FsManagerFlags>(),
+            Err(FsManagerFlagError::MissingValue(String::from(
+                "wait_timeout"
+            )))
+        );
+        assert_eq!(
+            "verity=1".parse::<FsManagerFlags>(),
+            Err(FsManagerFlagError::UnexpectedValue(String::from("verity")))
+        );
+
+        let fstab = "/dev/block/by-name/data /data ext4 noatime reservedsize=lots\n";
+        assert!(FsEntry::parse_strict(fstab, "a").is_err());
+        assert!(FsEntry::parse_entries(fstab, "a").unwrap().is_empty());
+    }
+
+    #[test]
+    fn device_specs() {
+        let fstab = r###"
+LABEL=rootfs                                    /       ext4    ro      first_stage_mount
+UUID=3b6e2f0d-6c1a-4e5b-9a1e-2c7d11223344       /data   ext4    rw      first_stage_mount
+PARTLABEL=vendor                                /vendor ext4    ro      slotselect,first_stage_mount
+PARTUUID=0d2f6e3b-1a6c-5b4e-9a1e-2c7d11223344   /boot   vfat    rw      first_stage_mount
+tmpfs                                           /tmp    tmpfs   rw      first_stage_mount
+"###;
+        let specs: Vec<DeviceSpec> = FsEntry::parse_entries(fstab, "b")
+            .unwrap()
+            .iter()
+            .map(|e| e.device_spec().unwrap())
+            .collect();
+        assert_eq!(
+            specs,
+            vec![
+                DeviceSpec::Label(String::from("rootfs")),
+                DeviceSpec::Uuid(String::from("3b6e2f0d-6c1a-4e5b-9a1e-2c7d11223344")),
+                DeviceSpec::PartLabel(String::from("vendor_b")),
+                DeviceSpec::PartUuid(String::from("0d2f6e3b-1a6c-5b4e-9a1e-2c7d11223344")),
+                DeviceSpec::Pseudo(String::from("tmpfs")),
+            ]
+        );
+        assert_eq!(specs[2].to_string(), "PARTLABEL=vendor_b");
+        assert_eq!(
+            "/dev/block/by-name/system_a".parse::<DeviceSpec>().unwrap(),
+            DeviceSpec::Path(PathBuf::from("/dev/block/by-name/system_a"))
+        );
+        assert!("LABEL=".parse::<DeviceSpec>().is_err());
+        assert!("block/vda".parse::<DeviceSpec>().is_err());
+
+        // the slot suffix is appended to a label, a UUID is the same in every slot
+        let entries = FsEntry::parse_entries("LABEL=system / ext4 ro slotselect\n", "a").unwrap();
+        assert_eq!(
+            entries[0].device_spec().unwrap(),
+            DeviceSpec::Label(String::from("system_a"))
+        );
+        for spec in ["UUID=3b6e2f0d", "PARTUUID=0d2f6e3b"].iter() {
+            let fstab = format!("{} / ext4 ro first_stage_mount,slotselect\n", spec);
+            let error = FsEntry::parse_strict(&fstab, "a").unwrap_err();
+            assert_eq!(error.reason, FstabErrorReason::SlotSelectWithUuid);
+            assert_eq!(error.column, 1);
+            assert!(FsEntry::parse_lenient(&fstab, "a").0.is_empty());
+        }
+    }
+
+    #[test]
+    fn strict_and_lenient() {
+        let fstab =
+            "# comment\n/dev/block/by-name/data /data ext4 nosiud,nodev first_stage_mount\n";
+        let error = FsEntry::parse_strict(fstab, "a").unwrap_err();
+        assert_eq!(
+            error,
+            FstabError {
+                line: 2,
+                column: 36,
+                reason: FstabErrorReason::UnknownMountOption,
+                token: String::from("nosiud"),
+            }
+        );
+        // parse_entries skips what it cannot use, as before
+        assert_eq!(
+            FsEntry::parse_entries(fstab, "a").unwrap(),
+            entries_of(fstab)
+        );
+
+        let (entries, warnings) = FsEntry::parse_lenient(fstab, "a");
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].data_options.to_str().unwrap(), "nosiud");
+        assert_eq!(warnings, vec![error]);
+
+        let fstab = "\
+/dev/block/by-name/system / ext4 ro first_stage_mount,frist_stage_mount
+/dev/block/by-name/vendor /vendor ext4 ro
+/dev/block/by-name/misc misc emmc defaults defaults
+/dev/block/by-name/data /data ext4 rw,context=u:object_r:a\0b first_stage_mount
+/dev/block/by-name/odm /odm ext4 ro wait_timeout=forever
+";
+        let (entries, warnings) = FsEntry::parse_lenient(fstab, "a");
+        assert_eq!(entries.len(), 1);
+        let found: Vec<(usize, usize, FstabErrorReason, &str)> = warnings
+            .iter()
+            .map(|w| (w.line, w.column, w.reason.clone(), w.token.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (1, 55, FstabErrorReason::UnknownFlag, "frist_stage_mount"),
+                (
+                    2,
+                    1,
+                    FstabErrorReason::WrongFieldCount(4),
+                    "/dev/block/by-name/vendor /vendor ext4 ro"
+                ),
+                (3, 25, FstabErrorReason::InvalidPath, "misc"),
+                (
+                    4,
+                    59,
+                    FstabErrorReason::NulByte,
+                    "rw,context=u:object_r:a\0b"
+                ),
+                (
+                    5,
+                    37,
+                    FstabErrorReason::InvalidFlag(FsManagerFlagError::InvalidValue {
+                        flag: String::from("wait_timeout"),
+                        value: String::from("forever"),
+                    }),
+                    "wait_timeout=forever"
+                ),
+            ]
+        );
+        assert_eq!(
+            FsEntry::parse_strict(fstab, "a").unwrap_err().reason,
+            FstabErrorReason::UnknownFlag
+        );
+    }
+
+    fn entries_of(fstab: &str) -> Vec<FsEntry> {
+        FsEntry::parse_lenient(fstab, "a").0
+    }
+
+    #[test]
+    fn android_fstab() {
+        // from a Pixel device, with lines of other devices for the flags they use
+        let fstab = r###"
+# Android fstab file.
+#<src>                                                  <mnt_point>        <type>      <mnt_flags and options>                               <fs_mgr_flags>
+system                                                  /system            ext4        ro,barrier=1                                          wait,slotselect,avb=vbmeta_system,logical,first_stage_mount,avb_keys=/avb/q-gsi.avbpubkey:/avb/r-gsi.avbpubkey:/avb/s-gsi.avbpubkey
+system_ext                                              /system_ext        ext4        ro,barrier=1                                          wait,slotselect,avb=vbmeta_system,logical,first_stage_mount
+vendor                                                  /vendor            ext4        ro,barrier=1                                          wait,slotselect,avb=vbmeta,logical,first_stage_mount
+system                                                  /mnt/system        ext4        ro,barrier=1                                          wait,slotselect_other,logical
+/dev/block/by-name/metadata                             /metadata          f2fs        noatime,nosuid,nodev,sync                             wait,check,formattable,wrappedkey,first_stage_mount
+/dev/block/by-name/boot                                 /boot              emmc        defaults                                              slotselect,avb=boot,first_stage_mount
+/dev/block/platform/14700000.ufs/by-name/efs            /mnt/vendor/efs    ext4        noatime,sync,journal_checksum,noload,journal_ioprio=0 wait,check,formattable
+/dev/block/platform/14700000.ufs/by-name/persist        /mnt/vendor/persist ext4       noatime,nosuid,nodev,grpid                            wait,check,formattable
+/dev/block/platform/14700000.ufs/by-name/userdata       /data              f2fs        noatime,nosuid,nodev,discard,reserve_root=32768,resgid=1065,fsync_mode=nobarrier,compress_extension=apk,inlinecrypt,atgc,gc_merge,checkpoint_merge,compress_mode=user,discard_unit=section latemount,wait,check,quota,formattable,sysfs_path=/dev/sys/block/bootdevice,checkpoint=fs,reservedsize=128M,fscompress,readahead_size_kb=128,keydirectory=/metadata/vold/metadata_encryption,fileencryption=::inlinecrypt_optimized+wrappedkey_v0
+/dev/block/platform/14700000.ufs/by-name/modem          /vendor/firmware_mnt vfat      ro,shortname=lower,uid=1000,gid=1000,dmask=227,fmask=337,context=u:object_r:firmware_file:s0 wait,slotselect
+/devices/platform/11210000.usb/11210000.dwc3*           auto               vfat        defaults                                              voldmanaged=usb:auto
+/dev/block/zram0                                        none               swap        defaults                                              zramsize=3221225472,max_comp_streams=8,zram_backingdev_size=512M
+"###;
+        let entries = FsEntry::parse_strict(fstab, "a").unwrap();
+        assert_eq!(entries, entries_of(fstab));
+        assert_eq!(entries.len(), 12);
+        assert_eq!(entries[0].fs_spec.to_str().unwrap(), "system_a");
+        assert_eq!(entries[3].fs_spec.to_str().unwrap(), "system");
+        assert_eq!(
+            entries[4].mount_options,
+            libc::MS_NOATIME | libc::MS_NOSUID | libc::MS_NODEV | libc::MS_SYNCHRONOUS
+        );
+        assert_eq!(
+            entries[6].data_options.to_str().unwrap(),
+            "journal_checksum,noload,journal_ioprio=0"
+        );
+        assert!(entries[8]
+            .fs_manager_flags
+            .contains(&FsManagerFlags::FileEncryption(FileEncryption {
+                contents_mode: String::new(),
+                filenames_mode: Some(String::new()),
+                options: Some(String::from("inlinecrypt_optimized+wrappedkey_v0")),
+            })));
+        assert_eq!(
+            entries[11].fs_manager_flags[0],
+            FsManagerFlags::ZramSize(ZramSize::Bytes(3 << 30))
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let contents = r###"# fstab for initrd
+/dev/block/by-name/system   /       ext4    ro,noauto,nouser    slotselect,first_stage_mount,verity
+
+#<dev> <mnt_point> <type> <mnt_flags options> <fs_mgr_flags>
+PARTLABEL=vendor    /vendor     ext4    ro,nodev,noatime    slotselect,first_stage_mount,avb=vbmeta_system
+/dev/block/by-name/data /data   f2fs    noatime,nosuid,nodev,discard,fsync_mode=nobarrier,checkpoint=disable    first_stage_mount,fileencryption=aes-256-xts:aes-256-cts:v2,reservedsize=128M,fsck_timeout=30,wait_timeout=500ms,quota
+tmpfs   /tmp    tmpfs   defaults,size=64m,mode=1777   defaults
+/dev/block/zram0    none    swap    defaults    zramsize=75%,verity_key=/etc/veritykey.pub
+"###;
+        let fstab = Fstab::parse(contents, "b").unwrap();
+        assert_eq!(fstab.entries().count(), 5);
+        assert_eq!(fstab.lines.len(), 8);
+
+        let serialized = fstab.serialize().unwrap();
+        assert_eq!(
+            serialized.lines().collect::<Vec<&str>>(),
+            vec![
+                "# fstab for initrd",
+                "/dev/block/by-name/system / ext4 ro slotselect,first_stage_mount,verity",
+                "",
+                "#<dev> <mnt_point> <type> <mnt_flags options> <fs_mgr_flags>",
+                "PARTLABEL=vendor /vendor ext4 ro,noatime,nodev slotselect,first_stage_mount,avb=vbmeta_system",
+                "/dev/block/by-name/data /data f2fs rw,noatime,nodev,nosuid,discard,fsync_mode=nobarrier,checkpoint=disable first_stage_mount,fileencryption=aes-256-xts:aes-256-cts:v2,reservedsize=128M,fsck_timeout=30,wait_timeout=500ms,quota",
+                "tmpfs /tmp tmpfs rw,size=64m,mode=1777 defaults",
+                "/dev/block/zram0 none swap rw zramsize=75%,verity_key=/etc/veritykey.pub",
+            ]
+        );
+        assert_eq!(Fstab::parse(&serialized, "b").unwrap(), fstab);
+
+        // an entry on its own keeps the slot suffix of its device
+        let system = fstab.entries().next().unwrap();
+        assert_eq!(
+            system.to_line().unwrap(),
+            "/dev/block/by-name/system_b / ext4 ro slotselect,first_stage_mount,verity"
+        );
+
+        // drop verity, as in an engineering build
+        let mut fstab = fstab;
+        for entry in fstab.entries_mut() {
+            entry
+                .fs_manager_flags
+                .retain(|f| *f != FsManagerFlags::Verity);
+        }
+        let serialized = fstab.serialize().unwrap();
+        assert!(serialized
+            .contains("/dev/block/by-name/system / ext4 ro slotselect,first_stage_mount\n"));
+        let reparsed = Fstab::parse(&serialized, "b").unwrap();
+        assert_eq!(reparsed, fstab);
+        assert!(!reparsed.entries().any(|e| e.is_verity_protected()));
+        assert_eq!(
+            reparsed.into_entries(),
+            FsEntry::parse_entries(&serialized, "b").unwrap()
+        );
+    }
+
+    #[test]
+    fn refuse_lossy_serialization() {
+        let mut fstab = Fstab::parse("tmpfs /tmp tmpfs nosuid,size=64m defaults\n", "a").unwrap();
+        let entry = fstab.entries_mut().next().unwrap();
+        entry.mount_options |= libc::MS_BIND;
+        assert_eq!(
+            entry.to_line(),
+            Err(FstabWriteError::UnnamedMountFlags(libc::MS_BIND))
+        );
+        assert!(fstab.serialize().is_err());
+
+        let entry = fstab.entries_mut().next().unwrap();
+        entry.mount_options = libc::MS_NOSUID;
+        entry.data_options = CString::new(vec![b's', 0xff]).unwrap();
+        assert_eq!(
+            entry.to_line(),
+            Err(FstabWriteError::NotUtf8("data options"))
+        );
+        assert_eq!(
+            fstab.serialize(),
+            Err(FstabWriteError::NotUtf8("data options"))
+        );
+
+        let entry = fstab.entries_mut().next().unwrap();
+        entry.data_options = CString::new("size=64m").unwrap();
+        assert_eq!(
+            fstab.serialize().unwrap(),
+            "tmpfs /tmp tmpfs rw,nosuid,size=64m defaults\n"
+        );
+    }
+
+    #[test]
+    fn flags_round_trip() {
+        let flags = vec![
+            FsManagerFlags::FirstStageMount,
+            FsManagerFlags::SlotSelect,
+            FsManagerFlags::Logical,
+            FsManagerFlags::Verity,
+            FsManagerFlags::Avb(None),
+            FsManagerFlags::Avb(Some(String::from("vbmeta_vendor"))),
+            FsManagerFlags::FileEncryption(FileEncryption {
+                contents_mode: String::from("aes-256-xts"),
+                filenames_mode: None,
+                options: None,
+            }),
+            FsManagerFlags::ZramSize(ZramSize::Bytes(1536 << 20)),
+            FsManagerFlags::ZramSize(ZramSize::Bytes(4097)),
+            FsManagerFlags::ReservedSize(2 << 30),
+            FsManagerFlags::FsckTimeout(Duration::from_millis(1500)),
+            FsManagerFlags::WaitTimeout(Duration::from_secs(5)),
+            FsManagerFlags::Other(String::from("sysfs_path=/sys/devices/platform/soc")),
+        ];
+        let serialized: Vec<String> = flags.iter().map(|f| f.to_string()).collect();
+        assert_eq!(serialized[7], "zramsize=1536M");
+        assert_eq!(serialized[8], "zramsize=4097");
+        assert_eq!(serialized[9], "reservedsize=2G");
+        assert_eq!(serialized[10], "fsck_timeout=1500ms");
+        let parsed: Vec<FsManagerFlags> = serialized.iter().map(|f| f.parse().unwrap()).collect();
+        assert_eq!(parsed, flags);
+    }
 }
@@ -0,0 +1,290 @@
+/*
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+/*
+  Identify block devices the way blkid does, so that fstab entries can name
+  them with LABEL=, UUID= and PARTUUID=. There is no udev in the initrd, so
+  the filesystem superblocks and the GPT are read directly.
+
+  Filesystems that are recognized: ext2/3/4, f2fs and vfat.
+*/
+
+use std::{fs::File, os::unix::fs::FileExt};
+
+/// Number of bytes to read from the start of a device to identify its filesystem
+pub const SUPERBLOCK_PROBE_SIZE: usize = 4096;
+
+const EXT_SUPERBLOCK_OFFSET: usize = 1024;
+const EXT_MAGIC: u16 = 0xEF53;
+const F2FS_SUPERBLOCK_OFFSET: usize = 1024;
+const F2FS_MAGIC: u32 = 0xF2F52010;
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+
+/// UUID and label of a filesystem
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FsIdentity {
+    pub uuid: Option<String>,
+    pub label: Option<String>,
+}
+
+/// Identify the filesystem from the first SUPERBLOCK_PROBE_SIZE bytes of a device.
+/// None if the filesystem is not recognized.
+pub fn probe_filesystem(data: &[u8]) -> Option<FsIdentity> {
+    probe_ext(data)
+        .or_else(|| probe_f2fs(data))
+        .or_else(|| probe_vfat(data))
+}
+
+fn probe_ext(data: &[u8]) -> Option<FsIdentity> {
+    let sb = data.get(EXT_SUPERBLOCK_OFFSET..EXT_SUPERBLOCK_OFFSET + 136)?;
+    if u16::from_le_bytes([sb[56], sb[57]]) != EXT_MAGIC {
+        return None;
+    }
+    Some(FsIdentity {
+        uuid: Some(format_uuid(&sb[104..120])),
+        label: label_from_bytes(&sb[120..136]),
+    })
+}
+
+fn probe_f2fs(data: &[u8]) -> Option<FsIdentity> {
+    let sb = data.get(F2FS_SUPERBLOCK_OFFSET..F2FS_SUPERBLOCK_OFFSET + 124 + 1024)?;
+    if u32::from_le_bytes([sb[0], sb[1], sb[2], sb[3]]) != F2FS_MAGIC {
+        return None;
+    }
+    // the volume name is UTF-16
+    let name: Vec<u16> = sb[124..]
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|c| *c != 0)
+        .collect();
+    let label = String::from_utf16(&name).ok().filter(|l| !l.is_empty());
+    Some(FsIdentity {
+        uuid: Some(format_uuid(&sb[108..124])),
+        label,
+    })
+}
+
+fn probe_vfat(data: &[u8]) -> Option<FsIdentity> {
+    let boot_sector = data.get(0..512)?;
+    if boot_sector[510..512] != [0x55, 0xAA] {
+        return None;
+    }
+    // offset of the extended boot signature for FAT32, then FAT12/16
+    let extended = if boot_sector[0x52..0x57] == *b"FAT32" {
+        0x42
+    } else if boot_sector[0x36..0x39] == *b"FAT" {
+        0x26
+    } else {
+        return None;
+    };
+    if boot_sector[extended] != 0x29 {
+        return Some(FsIdentity::default());
+    }
+
+    let id = &boot_sector[extended + 1..extended + 5];
+    let label =
+        label_from_bytes(&boot_sector[extended + 5..extended + 16]).filter(|l| l != "NO NAME");
+    Some(FsIdentity {
+        uuid: Some(format!(
+            "{:02X}{:02X}-{:02X}{:02X}",
+            id[3], id[2], id[1], id[0]
+        )),
+        label,
+    })
+}
+
+/// Read the unique partition GUID of a partition from the GPT of the disk.
+/// The partition number is the PARTN of the partition, starting at 1.
+pub fn gpt_partition_uuid(
+    disk: &File,
+    block_size: u64,
+    partition_number: u32,
+) -> Result<Option<String>, std::io::Error> {
+    let mut header = [0u8; 92];
+    disk.read_exact_at(&mut header, block_size)?;
+    if &header[0..8] != GPT_SIGNATURE {
+        return Ok(None);
+    }
+
+    let entries_lba = read_u64(&header[72..80]);
+    let number_of_entries = read_u32(&header[80..84]);
+    let entry_size = read_u32(&header[84..88]) as u64;
+    if partition_number == 0 || partition_number > number_of_entries || entry_size < 32 {
+        return Ok(None);
+    }
+
+    let offset = entries_lba
+        .checked_mul(block_size)
+        .and_then(|o| o.checked_add((partition_number as u64 - 1) * entry_size))
+        .ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid GPT header")
+        })?;
+    let mut entry = [0u8; 32];
+    disk.read_exact_at(&mut entry, offset)?;
+    if entry[0..16] == [0u8; 16] {
+        // unused entry
+        return Ok(None);
+    }
+    Ok(Some(format_guid(&entry[16..32])))
+}
+
+/// Get the value for the key from the contents of a sysfs uevent file
+pub fn uevent_value<'a>(contents: &'a str, key: &str) -> Option<&'a str> {
+    contents.lines().find_map(|line| {
+        line.split_once('=')
+            .filter(|(k, _)| *k == key)
+            .map(|(_, value)| value)
+    })
+}
+
+/// Format a UUID that is stored in big endian, as in the ext4 superblock
+fn format_uuid(bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        hex[0..4].concat(),
+        hex[4..6].concat(),
+        hex[6..8].concat(),
+        hex[8..10].concat(),
+        hex[10..16].concat()
+    )
+}
+
+/// Format a GUID as stored in the GPT, the first three fields are little endian
+fn format_guid(bytes: &[u8]) -> String {
+    let mut uuid = [0u8; 16];
+    uuid.copy_from_slice(&bytes[0..16]);
+    uuid[0..4].reverse();
+    uuid[4..6].reverse();
+    uuid[6..8].reverse();
+    format_uuid(&uuid)
+}
+
+/// A label in a fixed size field, padded with NUL bytes or spaces
+fn label_from_bytes(bytes: &[u8]) -> Option<String> {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    let label = String::from_utf8_lossy(&bytes[0..end])
+        .trim_end()
+        .to_string();
+    if label.is_empty() {
+        None
+    } else {
+        Some(label)
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut value = [0u8; 8];
+    value.copy_from_slice(&bytes[0..8]);
+    u64::from_le_bytes(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bootloader::storage::temp_path;
+    use std::io::Write;
+
+    #[test]
+    fn probe_superblocks() {
+        let uuid = [
+            0x3b, 0x6e, 0x2f, 0x0d, 0x6c, 0x1a, 0x4e, 0x5b, 0x9a, 0x1e, 0x2c, 0x7d, 0x11, 0x22,
+            0x33, 0x44,
+        ];
+
+        let mut ext4 = vec![0u8; SUPERBLOCK_PROBE_SIZE];
+        ext4[1024 + 56..1024 + 58].copy_from_slice(&EXT_MAGIC.to_le_bytes());
+        ext4[1024 + 104..1024 + 120].copy_from_slice(&uuid);
+        ext4[1024 + 120..1024 + 124].copy_from_slice(b"data");
+        assert_eq!(
+            probe_filesystem(&ext4),
+            Some(FsIdentity {
+                uuid: Some(String::from("3b6e2f0d-6c1a-4e5b-9a1e-2c7d11223344")),
+                label: Some(String::from("data")),
+            })
+        );
+
+        let mut f2fs = vec![0u8; SUPERBLOCK_PROBE_SIZE];
+        f2fs[1024..1028].copy_from_slice(&F2FS_MAGIC.to_le_bytes());
+        f2fs[1024 + 108..1024 + 124].copy_from_slice(&uuid);
+        for (index, c) in "userdata".encode_utf16().enumerate() {
+            let offset = 1024 + 124 + 2 * index;
+            f2fs[offset..offset + 2].copy_from_slice(&c.to_le_bytes());
+        }
+        let identity = probe_filesystem(&f2fs).unwrap();
+        assert_eq!(identity.label.as_deref(), Some("userdata"));
+        assert_eq!(
+            identity.uuid.as_deref(),
+            Some("3b6e2f0d-6c1a-4e5b-9a1e-2c7d11223344")
+        );
+
+        let mut vfat = vec![0u8; SUPERBLOCK_PROBE_SIZE];
+        vfat[510..512].copy_from_slice(&[0x55, 0xAA]);
+        vfat[0x52..0x5A].copy_from_slice(b"FAT32   ");
+        vfat[0x42] = 0x29;
+        vfat[0x43..0x47].copy_from_slice(&[0x34, 0x12, 0xCD, 0xAB]);
+        vfat[0x47..0x52].copy_from_slice(b"BOOT       ");
+        assert_eq!(
+            probe_filesystem(&vfat),
+            Some(FsIdentity {
+                uuid: Some(String::from("ABCD-1234")),
+                label: Some(String::from("BOOT")),
+            })
+        );
+
+        assert_eq!(probe_filesystem(&[0u8; SUPERBLOCK_PROBE_SIZE]), None);
+        assert_eq!(probe_filesystem(&[0u8; 16]), None);
+    }
+
+    #[test]
+    fn read_gpt_partition_uuid() {
+        let path = temp_path("block_id_read_gpt_partition_uuid.img");
+        let mut disk = vec![0u8; 512 * 4];
+        disk[512..520].copy_from_slice(GPT_SIGNATURE);
+        disk[512 + 72..512 + 80].copy_from_slice(&2u64.to_le_bytes());
+        disk[512 + 80..512 + 84].copy_from_slice(&4u32.to_le_bytes());
+        disk[512 + 84..512 + 88].copy_from_slice(&128u32.to_le_bytes());
+        // second entry
+        let entry = 1024 + 128;
+        disk[entry..entry + 16].copy_from_slice(&[0xaf; 16]);
+        disk[entry + 16..entry + 32].copy_from_slice(&[
+            0x0d, 0x2f, 0x6e, 0x3b, 0x1a, 0x6c, 0x5b, 0x4e, 0x9a, 0x1e, 0x2c, 0x7d, 0x11, 0x22,
+            0x33, 0x44,
+        ]);
+        File::create(&path).unwrap().write_all(&disk).unwrap();
+
+        let file = File::open(&path).unwrap();
+        assert_eq!(
+            gpt_partition_uuid(&file, 512, 2).unwrap().as_deref(),
+            Some("3b6e2f0d-6c1a-4e5b-9a1e-2c7d11223344")
+        );
+        assert_eq!(gpt_partition_uuid(&file, 512, 1).unwrap(), None);
+        assert_eq!(gpt_partition_uuid(&file, 512, 5).unwrap(), None);
+        assert_eq!(gpt_partition_uuid(&file, 4096, 2).ok().flatten(), None);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn uevent_values() {
+        let uevent = "MAJOR=259\nMINOR=3\nDEVNAME=nvme0n1p3\nDEVTYPE=partition\nPARTN=3\nPARTNAME=system_a\n";
+        assert_eq!(uevent_value(uevent, "DEVNAME"), Some("nvme0n1p3"));
+        assert_eq!(uevent_value(uevent, "PARTN"), Some("3"));
+        assert_eq!(uevent_value(uevent, "PARTUUID"), None);
+    }
+}
@@ -14,14 +14,18 @@
 
 use std::{
     ffi::{CStr, CString},
-    io::Error,
+    io::{Error, Read},
     os::unix::prelude::OsStrExt,
     path::{Path, PathBuf},
 };
 
+use crate::bootloader::bootcontrol::{slot_suffix_from_index, VerifiedBootControl};
+use crate::fstab::*;
+use crate::mount::block_id::{
+    gpt_partition_uuid, probe_filesystem, uevent_value, FsIdentity, SUPERBLOCK_PROBE_SIZE,
+};
+use crate::mount::verity::{handle_verity_corruption, Dm};
 use crate::uevent::*;
-use crate::{fstab::*, mount::verity::Dm};
-use sabaton_hal::bootloader::BootControl;
 
 pub const VBMETA_PARTITION_NAME_WITHOUT_SUFFIX: &str = "/dev/block/by-name/vbmeta";
 pub const MISC_PARTITION_NAME: &str = "/dev/block/by-name/misc";
@@ -44,12 +48,16 @@ fn should_prepare_verity(fstab_entries: &[FsEntry]) -> bool {
     false
 }
 
-/// Mount all the partitions that are marked for early mount
-pub fn mount_early_partitions(boot_hal: &mut dyn BootControl) -> Result<(), std::io::Error> {
+/// Mount all the partitions that are marked for early mount. Once the verity
+/// protected partitions are mounted, a dm-verity corruption marks the current
+/// slot as corrupted and reboots.
+pub fn mount_early_partitions(
+    boot_hal: &mut dyn VerifiedBootControl,
+) -> Result<(), std::io::Error> {
     let fstab_contents = std::fs::read_to_string(FSTAB_LOCATION)?;
     let root_temp_mount = CString::new("/new_root").unwrap();
 
-    let suffix = boot_hal.partition_suffix(boot_hal.current_slot()?)?;
+    let suffix = slot_suffix_from_index(boot_hal.current_slot()?)?;
     let mut fstab_entries = FsEntry::parse_entries(&fstab_contents, suffix)?;
 
     let mut socket = create_and_bind_netlink_socket().unwrap();
@@ -64,10 +72,20 @@ pub fn mount_early_partitions(boot_hal: &mut dyn BootControl) -> Result<(), std:
             log::error!("Cannot create device for {}", verity_partition_name);
             e
         })?;
-        let dm = Dm::new(Path::new(&verity_partition_name)).map_err(|_e| {
+        let mut dm = Dm::new(Path::new(&verity_partition_name)).map_err(|_e| {
             log::error!("DM setup error");
             std::io::Error::from(std::io::ErrorKind::Other)
         })?;
+        let current_slot = boot_hal.current_slot()?;
+        match boot_hal.rollback_index_storage() {
+            Some(storage) => dm
+                .check_rollback_index(storage, current_slot)
+                .map_err(|e| {
+                    log::error!("Rollback protection : {}", e);
+                    std::io::Error::from(std::io::ErrorKind::PermissionDenied)
+                })?,
+            None => dm.skip_rollback_index_check(),
+        }
 
         log::info!("DM Open Success");
         (Some(dm), Some(PathBuf::from(verity_partition_name)))
@@ -84,8 +102,11 @@ pub fn mount_early_partitions(boot_hal: &mut dyn BootControl) -> Result<(), std:
         } else {
             let mut count = 5;
             while count > 0 {
-                if ensure_mount_device_is_created(root.fs_spec.as_c_str(), &mut socket).is_ok() {
+                if let Ok(device) =
+                    ensure_mount_device_is_created(root.fs_spec.as_c_str(), &mut socket)
+                {
                     log::info!("early mount devices created");
+                    root.fs_spec = CString::new(device.as_os_str().as_bytes())?;
                     break;
                 } else {
                     log::info!(
@@ -110,10 +131,10 @@ pub fn mount_early_partitions(boot_hal: &mut dyn BootControl) -> Result<(), std:
                     verity_partition_name.as_ref().unwrap(),
                     &dm_device,
                 )?;
-                let device = create_dm_device_entry(&dm_device, &mut socket)?;
+                let device = create_block_device_entry(&dm_device, &mut socket)?;
                 let mut e = root.clone();
                 e.fs_spec = CString::new(device.to_str().unwrap()).unwrap();
-                mount_partition(&e)?;
+                mount_verity_partition(&e, boot_hal)?;
             } else {
                 mount_partition(root)?;
             }
@@ -129,12 +150,13 @@ pub fn mount_early_partitions(boot_hal: &mut dyn BootControl) -> Result<(), std:
     switch_to_new_root(&root_temp_mount)?;
 
     // now mount the other partitions
-    for e in fstab_entries {
+    for mut e in fstab_entries {
         // we have already mounted the root above, skip it
         if e.mountpoint == root_cmp {
             continue;
         }
-        ensure_mount_device_is_created(e.fs_spec.as_c_str(), &mut socket)?;
+        let device = ensure_mount_device_is_created(e.fs_spec.as_c_str(), &mut socket)?;
+        e.fs_spec = CString::new(device.as_os_str().as_bytes())?;
 
         if e.is_verity_protected() {
             let dm_device = format!("dm-{}", next_dm_index);
@@ -145,32 +167,40 @@ pub fn mount_early_partitions(boot_hal: &mut dyn BootControl) -> Result<(), std:
                 verity_partition_name.as_ref().unwrap(),
                 &dm_device,
             )?;
-            let device = create_dm_device_entry(&dm_device, &mut socket)?;
+            let device = create_block_device_entry(&dm_device, &mut socket)?;
             let mut e = e.clone();
             e.fs_spec = CString::new(device.to_str().unwrap()).unwrap();
-            mount_partition(&e)?;
+            mount_verity_partition(&e, boot_hal)?;
         } else {
             mount_partition(&e)?;
         }
     }
+
+    if dm.is_some() {
+        handle_verity_corruption(boot_hal)?;
+    }
     Ok(())
 }
 
-/// Create a device manager device entry
-/// device_name indicates thethe dm device created
-/// for example dm-0, dm-1, etc.
+/// Create the device entry for a block device.
+/// device_name is the kernel name of the device, for
+/// example dm-0 or mmcblk0p3.
 /// Returns the  path to the device that is created.
-fn create_dm_device_entry(
+fn create_block_device_entry(
     device_name: &str,
     nl_socket: &mut NLSocket,
 ) -> Result<PathBuf, std::io::Error> {
-    let device = PathBuf::from(format!("/sys/block/{}", device_name));
-    log::debug!("Create DM device for {}", device.display());
+    let device = Path::new("/dev/block").join(device_name);
+    if device.exists() {
+        return Ok(device);
+    }
+
+    let sys_device = Path::new("/sys/class/block").join(device_name);
+    log::debug!("Create device entry for {}", sys_device.display());
 
-    let _action = regenerate_uevent_for_dir(&device, nl_socket, &mut |e| {
+    let _action = regenerate_uevent_for_dir(&sys_device, nl_socket, &mut |e| {
         //log::debug!("Event {:?}", e);
 
-        // look for partition name if device is searched by name
         let matched = if let Some(p_name) = e.get_devname() {
             p_name == device_name
         } else {
@@ -185,8 +215,6 @@ fn create_dm_device_entry(
         }
     });
 
-    let device = Path::new("/dev/block").join(device_name);
-
     if !device.exists() {
         log::error!("{} device entry not created", device_name);
         Err(Error::new(std::io::ErrorKind::NotFound, "path not found"))
@@ -195,21 +223,49 @@ fn create_dm_device_entry(
     }
 }
 
-/// Create the device entry for the the provided entry. The device entries can be
-/// of the form  /dev/block/<name>  or /dev/block/by-name/<partition-name>
+/// Create the device entry for the the provided fstab spec. The device can be
+/// given as /dev/block/<name>, /dev/block/by-name/<partition-name>, LABEL=,
+/// UUID=, PARTLABEL= or PARTUUID=.
+/// Returns the path to the device entry that is to be mounted.
 pub fn ensure_mount_device_is_created(
     fs_spec: &CStr,
     nl_socket: &mut NLSocket,
-) -> Result<(), std::io::Error> {
-    let path = Path::new(fs_spec.to_str().unwrap());
-    log::debug!("ensure dev created for : {}", path.display());
-    // we allow early mounting of tmpfs
-    if path == Path::new("tmpfs") {
-        return Ok(());
+) -> Result<PathBuf, std::io::Error> {
+    let spec = fs_spec
+        .to_str()
+        .map_err(|e| Error::new(std::io::ErrorKind::InvalidInput, e))?
+        .parse::<DeviceSpec>()?;
+    log::debug!("ensure dev created for : {}", spec);
+
+    match spec {
+        // we allow early mounting of tmpfs and other pseudo filesystems
+        DeviceSpec::Pseudo(name) => Ok(PathBuf::from(name)),
+        DeviceSpec::Path(path) => {
+            ensure_device_path_is_created(&path, nl_socket)?;
+            Ok(path)
+        }
+        DeviceSpec::PartLabel(label) => {
+            let path = Path::new("/dev/block/by-name").join(label);
+            ensure_device_path_is_created(&path, nl_socket)?;
+            Ok(path)
+        }
+        DeviceSpec::Label(_) | DeviceSpec::Uuid(_) | DeviceSpec::PartUuid(_) => {
+            find_tagged_device(&spec, nl_socket)
+        }
     }
+}
 
+/// Create the device entry for a path of the form /dev/block/<name>
+/// or /dev/block/by-name/<partition-name>
+fn ensure_device_path_is_created(
+    path: &Path,
+    nl_socket: &mut NLSocket,
+) -> Result<(), std::io::Error> {
     if !path.starts_with("/dev/block") {
-        panic!("filesystem spec in fstab must start with /dev/block");
+        return Err(Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} is not in /dev/block", path.display()),
+        ));
     }
 
     // return right away if the device already exists
@@ -221,7 +277,9 @@ pub fn ensure_mount_device_is_created(
 
     if let Some(third) = path_components.next() {
         let (device_is_by_name, device_name, search_path) = if third.as_os_str() == "by-name" {
-            let device = path_components.next().expect("Expected device name");
+            let device = path_components.next().ok_or_else(|| {
+                Error::new(std::io::ErrorKind::InvalidInput, "Expected device name")
+            })?;
             // if we have the device by name we need to search broader
             (true, device, PathBuf::new().join("/sys/class/block"))
         } else {
@@ -269,6 +327,124 @@ pub fn ensure_mount_device_is_created(
     }
 }
 
+/// Find the device for a LABEL=, UUID= or PARTUUID= spec. The block devices
+/// are looked up in sysfs and their entries are created to read the
+/// superblock or the partition table. All devices are checked, a spec that
+/// matches more than one device is an error.
+fn find_tagged_device(
+    spec: &DeviceSpec,
+    nl_socket: &mut NLSocket,
+) -> Result<PathBuf, std::io::Error> {
+    let mut matches: Vec<String> = Vec::new();
+    for entry in std::fs::read_dir("/sys/class/block")? {
+        let sys_device = entry?.path();
+        let uevent = match std::fs::read_to_string(sys_device.join("uevent")) {
+            Ok(uevent) => uevent,
+            Err(_) => continue,
+        };
+        let device_name = match uevent_value(&uevent, "DEVNAME") {
+            Some(name) if !name.starts_with("loop") && !name.starts_with("ram") => name,
+            _ => continue,
+        };
+
+        let matched = match spec {
+            DeviceSpec::PartUuid(uuid) => {
+                match read_partition_uuid(&sys_device, &uevent, nl_socket) {
+                    Ok(Some(partition_uuid)) => partition_uuid.eq_ignore_ascii_case(uuid),
+                    Ok(None) => false,
+                    Err(e) => {
+                        log::debug!("Cannot read the GPT for {} : {}", device_name, e);
+                        false
+                    }
+                }
+            }
+            _ => match read_fs_identity(device_name, nl_socket) {
+                Ok(Some(identity)) => match spec {
+                    DeviceSpec::Uuid(uuid) => {
+                        matches!(identity.uuid, Some(u) if u.eq_ignore_ascii_case(uuid))
+                    }
+                    DeviceSpec::Label(label) => identity.label.as_ref() == Some(label),
+                    _ => false,
+                },
+                Ok(None) => false,
+                Err(e) => {
+                    log::debug!("Cannot read the superblock of {} : {}", device_name, e);
+                    false
+                }
+            },
+        };
+
+        if matched {
+            matches.push(String::from(device_name));
+        }
+    }
+
+    match matches.as_slice() {
+        [] => Err(Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("No device found for {}", spec),
+        )),
+        [device_name] => {
+            log::info!("{} is {}", spec, device_name);
+            create_block_device_entry(device_name, nl_socket)
+        }
+        _ => {
+            log::error!("{} matches {:?}", spec, matches);
+            Err(Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "More than one device found for {} : {}",
+                    spec,
+                    matches.join(", ")
+                ),
+            ))
+        }
+    }
+}
+
+/// Identify the filesystem on the device
+fn read_fs_identity(
+    device_name: &str,
+    nl_socket: &mut NLSocket,
+) -> Result<Option<FsIdentity>, std::io::Error> {
+    let device = create_block_device_entry(device_name, nl_socket)?;
+    let mut buffer = vec![0u8; SUPERBLOCK_PROBE_SIZE];
+    std::fs::File::open(device)?.read_exact(&mut buffer)?;
+    Ok(probe_filesystem(&buffer))
+}
+
+/// Read the unique GUID of a partition from the GPT of its disk
+fn read_partition_uuid(
+    sys_device: &Path,
+    uevent: &str,
+    nl_socket: &mut NLSocket,
+) -> Result<Option<String>, std::io::Error> {
+    if uevent_value(uevent, "DEVTYPE") != Some("partition") {
+        return Ok(None);
+    }
+    let partition_number = match uevent_value(uevent, "PARTN").and_then(|n| n.parse().ok()) {
+        Some(number) => number,
+        None => return Ok(None),
+    };
+
+    // in sysfs the partition is a child of its disk
+    let sys_disk = sys_device.canonicalize()?;
+    let sys_disk = sys_disk
+        .parent()
+        .ok_or_else(|| Error::new(std::io::ErrorKind::NotFound, "No parent disk"))?;
+    let disk_name = sys_disk
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| Error::new(std::io::ErrorKind::NotFound, "No parent disk"))?;
+    let block_size = std::fs::read_to_string(sys_disk.join("queue/logical_block_size"))
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(512);
+
+    let disk = std::fs::File::open(create_block_device_entry(disk_name, nl_socket)?)?;
+    gpt_partition_uuid(&disk, block_size, partition_number)
+}
+
 // Mount a verity protected partition
 fn create_dm_device(
     entry: &FsEntry,
@@ -297,21 +473,42 @@ fn create_dm_device(
     //mount_partition(&e)
 }
 
+/// Mount a verity protected partition. A failed mount can be caused by a
+/// corruption that dm-verity detected while the filesystem was probed.
+fn mount_verity_partition(
+    entry: &FsEntry,
+    boot_hal: &mut dyn VerifiedBootControl,
+) -> Result<(), std::io::Error> {
+    mount_partition(entry).map_err(|e| {
+        if let Err(verity_error) = handle_verity_corruption(boot_hal) {
+            log::error!("Cannot check for verity corruption : {}", verity_error);
+        }
+        e
+    })
+}
+
 fn mount_partition(entry: &FsEntry) -> Result<(), std::io::Error> {
     log::debug!(
-        "Going to mount {:?} to {:?} type:{:?}",
+        "Going to mount {:?} to {:?} type:{:?} data:{:?}",
         &entry.fs_spec,
         &entry.mountpoint,
-        &entry.vfs_type
+        &entry.vfs_type,
+        &entry.data_options
     );
 
+    let data = if entry.data_options.as_bytes().is_empty() {
+        std::ptr::null()
+    } else {
+        entry.data_options.as_ptr() as *const libc::c_void
+    };
+
     let ret = unsafe {
         libc::mount(
             entry.fs_spec.as_ptr(),
             entry.mountpoint.as_ptr(),
             entry.vfs_type.as_ptr(),
             entry.mount_options,
-            std::ptr::null_mut(),
+            data,
         )
     };
 
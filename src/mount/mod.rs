@@ -1,3 +1,4 @@
+pub mod block_id;
 pub mod early_mount;
 pub mod early_partitions;
 pub mod verity;
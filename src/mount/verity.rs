@@ -18,11 +18,17 @@ use devicemapper::{DevId, DmFlags, DmName, DmOptions, DM};
 use nix::ioctl_read;
 use sabaton_hal::verity::VerityPartitionHeader;
 
+use crate::bootloader::bootcontrol::VerifiedBootControl;
+use crate::bootloader::error::BootloaderMessageError;
+use crate::bootloader::rollback_index::{RollbackIndexes, VERITY_ROLLBACK_INDEX_LOCATION};
+use crate::bootloader::storage::MiscStorage;
 use crate::error::CoreError;
 
 pub struct Dm {
     dm: DM,
     partition_header: VerityPartitionHeader,
+    /// Set once the rollback index of the header was checked
+    rollback_index_checked: bool,
 }
 
 impl Dm {
@@ -74,19 +80,50 @@ impl Dm {
         Ok(Self {
             dm,
             partition_header,
+            rollback_index_checked: false,
         })
     }
 
+    /// Compare the rollback index of the verity header with the one stored in the
+    /// rollback index storage and stage it for the slot. It is only committed once
+    /// the slot is marked as successfully booted. Devices are not created until
+    /// this check passed.
+    pub fn check_rollback_index(
+        &mut self,
+        storage: &mut dyn MiscStorage,
+        slot_index: usize,
+    ) -> Result<(), CoreError> {
+        stage_verity_rollback_index(storage, slot_index, self.partition_header.rollback_index())?;
+        self.rollback_index_checked = true;
+        Ok(())
+    }
+
+    /// Create the devices without a rollback index check, for boot controls
+    /// that have no storage for the rollback indexes.
+    pub fn skip_rollback_index_check(&mut self) {
+        log::warn!(
+            "No rollback index storage, the verity header is not protected against rollback"
+        );
+        self.rollback_index_checked = true;
+    }
+
     pub fn create_dm_device(
         &self,
         protected_partition_from_fstab: &Path,
         verity_partition: &Path,
         name: &str,
     ) -> Result<(), CoreError> {
-        let protected_partition = protected_partition_from_fstab.canonicalize().map_err(|_e| {
-            log::error!("Canonicalize {}", protected_partition_from_fstab.display());
-            CoreError::InvalidArgument
-        })?;
+        if !self.rollback_index_checked {
+            log::error!("Rollback index of the verity header was not checked");
+            return Err(CoreError::DMError);
+        }
+
+        let protected_partition = protected_partition_from_fstab
+            .canonicalize()
+            .map_err(|_e| {
+                log::error!("Canonicalize {}", protected_partition_from_fstab.display());
+                CoreError::InvalidArgument
+            })?;
 
         let verity_partition = verity_partition.canonicalize().map_err(|_e| {
             log::error!("Canonicalize {}", verity_partition.display());
@@ -174,6 +211,143 @@ impl Dm {
     }
 }
 
+/// Check the rollback index of the verity header against the stored one and stage
+/// it for the slot. A corrupted store refuses the header, it is not reset.
+fn stage_verity_rollback_index(
+    storage: &mut dyn MiscStorage,
+    slot_index: usize,
+    rollback_index: u64,
+) -> Result<(), CoreError> {
+    let mut indexes = RollbackIndexes::load(storage).map_err(|e| {
+        log::error!("Cannot load the rollback indexes : {}", e);
+        CoreError::RollbackIndexCorrupted
+    })?;
+
+    indexes
+        .stage(slot_index, VERITY_ROLLBACK_INDEX_LOCATION, rollback_index)
+        .map_err(|e| {
+            log::error!(
+                "Verity header rollback index {} refused : {}",
+                rollback_index,
+                e
+            );
+            match e {
+                BootloaderMessageError::RollbackIndexTooLow => CoreError::RollbackIndexTooLow,
+                _ => CoreError::InvalidArgument,
+            }
+        })?;
+    indexes.save(storage).map_err(|e| {
+        log::error!("Cannot save rollback indexes : {}", e);
+        CoreError::DMError
+    })
+}
+
+/// Check the status line of a verity target. dm-verity reports 'V' while
+/// all blocks verified and 'C' once a corruption was detected.
+fn is_verity_status_corrupted(status: &str) -> bool {
+    status.trim_start().starts_with('C')
+}
+
+/// The status of the device mapper targets. Implemented over DM, tests use a fake.
+pub trait DmStatus {
+    /// The names of the device mapper devices
+    fn device_names(&self) -> Result<Vec<String>, CoreError>;
+    /// The target type and the status line of every target of the device
+    fn target_status(&self, name: &str) -> Result<Vec<(String, String)>, CoreError>;
+}
+
+impl DmStatus for DM {
+    fn device_names(&self) -> Result<Vec<String>, CoreError> {
+        let devices = self.list_devices().map_err(|e| {
+            log::error!("Cannot list DM devices: {}", e);
+            CoreError::DMError
+        })?;
+        Ok(devices
+            .iter()
+            .map(|(dm_name, _device, _event_nr)| {
+                String::from_utf8_lossy(dm_name.as_bytes()).into_owned()
+            })
+            .collect())
+    }
+
+    fn target_status(&self, name: &str) -> Result<Vec<(String, String)>, CoreError> {
+        let dm_name = DmName::new(name).map_err(|e| {
+            log::error!("Invalid DM name {}: {}", name, e);
+            CoreError::DMError
+        })?;
+        let (_info, status) = self
+            .table_status(&DevId::Name(dm_name), DmOptions::default())
+            .map_err(|e| {
+                log::error!("Cannot get status of {}: {}", name, e);
+                CoreError::DMError
+            })?;
+        Ok(status
+            .into_iter()
+            .map(|(_start, _length, target_type, params)| (target_type, params))
+            .collect())
+    }
+}
+
+/// Find the verity devices that have detected a corruption.
+/// Returns the names of the corrupted devices.
+pub fn find_corrupted_verity_devices(dm: &dyn DmStatus) -> Result<Vec<String>, CoreError> {
+    let mut corrupted = Vec::new();
+    for name in dm.device_names()? {
+        if dm
+            .target_status(&name)?
+            .iter()
+            .any(|(target_type, params)| {
+                target_type == "verity" && is_verity_status_corrupted(params)
+            })
+        {
+            log::error!("dm-verity corruption detected on {}", name);
+            corrupted.push(name);
+        }
+    }
+    Ok(corrupted)
+}
+
+/// Check the verity devices and mark the current slot as verity corrupted if
+/// any of them found a corruption. Returns true if a corruption was recorded.
+pub fn record_verity_corruption(
+    dm: &dyn DmStatus,
+    boot_control: &mut dyn VerifiedBootControl,
+) -> Result<bool, std::io::Error> {
+    let corrupted = find_corrupted_verity_devices(dm)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    if corrupted.is_empty() {
+        return Ok(false);
+    }
+
+    let current_slot = boot_control.current_slot()?;
+    log::error!("Slot {} is corrupted ({:?})", current_slot, corrupted);
+    boot_control.set_slot_verity_corrupted(current_slot)?;
+    Ok(true)
+}
+
+/// Check the verity devices and handle a corruption. The current slot is
+/// marked as verity corrupted and the system is rebooted so that the bootloader
+/// can fall back to the other slot. Returns Ok(()) if no corruption was found.
+pub fn handle_verity_corruption(
+    boot_control: &mut dyn VerifiedBootControl,
+) -> Result<(), std::io::Error> {
+    let dm = DM::new().map_err(|e| {
+        log::error!("Error opening DM {}", e);
+        std::io::Error::new(std::io::ErrorKind::Other, CoreError::DMError)
+    })?;
+    if !record_verity_corruption(&dm, boot_control)? {
+        return Ok(());
+    }
+
+    log::error!("Rebooting after dm-verity corruption");
+    unsafe {
+        libc::sync();
+        libc::reboot(libc::RB_AUTOBOOT);
+    }
+    // only reached if the reboot failed
+    Err(std::io::Error::last_os_error())
+}
+
 pub fn load_dm() -> Result<(), CoreError> {
     log::info!("load_dm");
     if let Ok(dm) = DM::new() {
@@ -211,3 +385,94 @@ fn get_device_size(path: &Path) -> u64 {
 
     cap
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bootloader::bootcontrol::BootControlImpl;
+    use crate::bootloader::rollback_index::ROLLBACK_INDEX_OFFSET_IN_MISC;
+    use crate::bootloader::storage::{misc_from_testdata, MemoryStorage, MiscStorage};
+    use sabaton_hal::bootloader::BootControl;
+
+    /// Device mapper devices with their (target type, status line)
+    struct FakeDmStatus(Vec<(&'static str, Vec<(&'static str, &'static str)>)>);
+
+    impl DmStatus for FakeDmStatus {
+        fn device_names(&self) -> Result<Vec<String>, CoreError> {
+            Ok(self.0.iter().map(|(name, _)| String::from(*name)).collect())
+        }
+
+        fn target_status(&self, name: &str) -> Result<Vec<(String, String)>, CoreError> {
+            let (_, targets) = self
+                .0
+                .iter()
+                .find(|(n, _)| *n == name)
+                .ok_or(CoreError::DMError)?;
+            Ok(targets
+                .iter()
+                .map(|(t, p)| (String::from(*t), String::from(*p)))
+                .collect())
+        }
+    }
+
+    #[test]
+    fn verity_status() {
+        assert!(!is_verity_status_corrupted("V"));
+        assert!(is_verity_status_corrupted("C"));
+        assert!(is_verity_status_corrupted("C 12"));
+        assert!(!is_verity_status_corrupted("V 0"));
+    }
+
+    #[test]
+    fn refuse_corrupted_rollback_indexes() {
+        let mut storage = MemoryStorage::new(64 * 1024);
+        stage_verity_rollback_index(&mut storage, 0, 3).unwrap();
+        let mut indexes = RollbackIndexes::load(&storage).unwrap();
+        indexes.commit(0).unwrap();
+        indexes.save(&mut storage).unwrap();
+        assert!(matches!(
+            stage_verity_rollback_index(&mut storage, 0, 2),
+            Err(CoreError::RollbackIndexTooLow)
+        ));
+
+        // a corrupted store is not replaced by zero indexes, the header is refused
+        let mut data = RollbackIndexes::load(&storage).unwrap().to_bytes();
+        data[16] ^= 0x01;
+        storage
+            .write_at(&data, ROLLBACK_INDEX_OFFSET_IN_MISC as u64)
+            .unwrap();
+        assert!(matches!(
+            stage_verity_rollback_index(&mut storage, 0, 3),
+            Err(CoreError::RollbackIndexCorrupted)
+        ));
+        assert_eq!(
+            &storage.as_slice()[ROLLBACK_INDEX_OFFSET_IN_MISC..][..data.len()],
+            &data[..]
+        );
+    }
+
+    #[test]
+    fn find_and_record_corruption() {
+        let dm = FakeDmStatus(vec![
+            ("dm-0", vec![("verity", "V")]),
+            ("dm-1", vec![("linear", "C"), ("verity", "C")]),
+            ("dm-2", vec![("linear", "C")]),
+        ]);
+        assert_eq!(find_corrupted_verity_devices(&dm).unwrap(), vec!["dm-1"]);
+
+        let mut boot_control =
+            BootControlImpl::create_from_storage(Box::new(misc_from_testdata())).unwrap();
+        let current_slot = boot_control.current_slot().unwrap();
+        let other_slot = 1 - current_slot;
+        assert!(boot_control.is_bootable(other_slot).unwrap());
+
+        let clean = FakeDmStatus(vec![("dm-0", vec![("verity", "V")])]);
+        assert!(!record_verity_corruption(&clean, &mut boot_control).unwrap());
+        assert!(!boot_control.is_slot_verity_corrupted(current_slot).unwrap());
+
+        assert!(record_verity_corruption(&dm, &mut boot_control).unwrap());
+        assert!(boot_control.is_slot_verity_corrupted(current_slot).unwrap());
+        assert!(!boot_control.is_bootable(current_slot).unwrap());
+        assert!(boot_control.is_bootable(other_slot).unwrap());
+    }
+}